@@ -125,6 +125,7 @@ async fn auto_compaction_remote_emits_started_and_completed_items() -> Result<()
             content: vec![ContentItem::OutputText {
                 text: "REMOTE_COMPACT_SUMMARY".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         ResponseItem::Compaction {
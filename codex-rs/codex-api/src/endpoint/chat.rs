@@ -193,6 +193,7 @@ impl Stream for AggregatedStream {
                             content: vec![ContentItem::OutputText {
                                 text: std::mem::take(&mut this.cumulative),
                             }],
+                            name: None,
                             end_turn: None,
                         };
                         this.pending
@@ -1,4 +1,6 @@
 use crate::rate_limits::RateLimitError;
+use chrono::DateTime;
+use chrono::Utc;
 use codex_client::TransportError;
 use http::StatusCode;
 use std::time::Duration;
@@ -27,6 +29,59 @@ pub enum ApiError {
     RateLimit(String),
     #[error("invalid request: {message}")]
     InvalidRequest { message: String },
+    #[error("history too long: {count} messages exceeds the limit of {limit}")]
+    HistoryTooLong { count: usize, limit: usize },
+    #[error("tool output references unknown call id {call_id}")]
+    DanglingToolOutput { call_id: String },
+    #[error("invalid image url: {message}")]
+    InvalidImageUrl { message: String },
+    #[error("unsupported image type {mime}")]
+    UnsupportedImageMime { mime: String },
+    #[error("duplicate tool call id {call_id}")]
+    DuplicateToolCallId { call_id: String },
+    #[error("too many images: {count} exceeds the limit of {limit}")]
+    TooManyImages { count: usize, limit: usize },
+    #[error("instructions are {bytes} bytes, exceeding the limit of {limit}")]
+    InstructionsTooLarge { bytes: usize, limit: usize },
+    #[error("reasoning would attach to the user message at input index {index}")]
+    ReasoningOnUserMessage { index: usize },
+    #[error("request input is empty")]
+    EmptyInput,
+    #[error("too many tools: {count} exceeds the limit of {limit}")]
+    TooManyTools { count: usize, limit: usize },
+    #[error("tool call {call_id} has no matching output")]
+    UnresolvedToolCall { call_id: String },
+    #[error("reasoning options are set but reasoning is not enabled")]
+    ReasoningOptionsWithoutReasoning,
+    #[error("image is {bytes} bytes, exceeding the limit of {limit}")]
+    ImageTooLarge { bytes: usize, limit: usize },
+    #[error("rate limited")]
+    RateLimited { retry_after: Option<Duration> },
+}
+
+impl ApiError {
+    /// Builds [`ApiError::RateLimited`] from a raw `Retry-After` header value, which is either
+    /// a number of seconds or an HTTP-date. Unparseable values yield no delay.
+    pub fn rate_limited(retry_after: Option<&str>) -> Self {
+        Self::RateLimited {
+            retry_after: retry_after.and_then(|value| parse_retry_after(value, Utc::now())),
+        }
+    }
+}
+
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past means the client may retry immediately.
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
 }
 
 impl From<RateLimitError> for ApiError {
@@ -34,3 +89,42 @@ impl From<RateLimitError> for ApiError {
         Self::RateLimit(err.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use pretty_assertions::assert_eq;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc2822("Sun, 06 Nov 1994 08:49:37 GMT")
+            .expect("valid date")
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(
+            parse_retry_after("120", now()),
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn parses_retry_after_http_date() {
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now()),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now()),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn rate_limited_ignores_unparseable_values() {
+        let err = ApiError::rate_limited(Some("soon"));
+        assert_matches!(err, ApiError::RateLimited { retry_after: None });
+    }
+}
@@ -35,7 +35,15 @@ pub use crate::provider::Provider;
 pub use crate::provider::WireApi;
 pub use crate::requests::ChatRequest;
 pub use crate::requests::ChatRequestBuilder;
+pub use crate::requests::EmbeddingsRequest;
+pub use crate::requests::EmbeddingsRequestBuilder;
+pub use crate::requests::ImageRequest;
+pub use crate::requests::ImageRequestBuilder;
+pub use crate::requests::ModerationRequest;
+pub use crate::requests::ModerationRequestBuilder;
+pub use crate::requests::ReasoningFieldStyle;
 pub use crate::requests::ResponsesRequest;
 pub use crate::requests::ResponsesRequestBuilder;
+pub use crate::requests::ToolChoice;
 pub use crate::sse::stream_from_fixture;
 pub use crate::telemetry::SseTelemetry;
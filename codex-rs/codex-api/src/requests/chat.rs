@@ -9,23 +9,410 @@ use codex_protocol::models::ReasoningItemContent;
 use codex_protocol::models::ResponseItem;
 use codex_protocol::protocol::SessionSource;
 use http::HeaderMap;
+use serde_json::Map;
 use serde_json::Value;
 use serde_json::json;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Chat Completions backends accept at most this many `stop` sequences.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Upper bound accepted by Chat Completions for `top_logprobs`.
+const MAX_TOP_LOGPROBS: u8 = 20;
+
+/// Longest value accepted in the request-level `metadata` object.
+const MAX_METADATA_VALUE_CHARS: usize = 512;
+
+/// Stand-in for images when tool output is flattened to plain text.
+const FLAT_TOOL_OUTPUT_IMAGE_PLACEHOLDER: &str = "[image omitted]";
+
+/// Approximate per-message framing cost (role markers, separators) used by token estimates.
+const MESSAGE_TOKEN_OVERHEAD: usize = 4;
+
+/// Image types providers accept in `data:` URIs; others are rejected in strict mode.
+const SUPPORTED_IMAGE_MIME_TYPES: [&str; 4] =
+    ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// Header carrying the subagent label unless overridden by the builder.
+const DEFAULT_SUBAGENT_HEADER: &str = "x-openai-subagent";
 
 /// Assembled request body plus headers for Chat Completions streaming calls.
 pub struct ChatRequest {
     pub body: Value,
     pub headers: HeaderMap,
+    /// Ids of every emitted `tool_calls` entry, in order, so callers can check that each has
+    /// a matching output.
+    pub tool_call_ids: Vec<String>,
+}
+
+impl ChatRequest {
+    /// The body as pretty-printed JSON, for debugging and logging.
+    pub fn pretty_body(&self) -> String {
+        serde_json::to_string_pretty(&self.body).unwrap_or_default()
+    }
+
+    /// Like [`Self::pretty_body`], but with `data:` image URLs replaced by an
+    /// `<image:N bytes>` placeholder, where N is the decoded image size, so logs stay readable.
+    pub fn redacted_pretty_body(&self) -> String {
+        let mut body = self.body.clone();
+        redact_image_data_urls(&mut body);
+        serde_json::to_string_pretty(&body).unwrap_or_default()
+    }
+
+    /// Number of messages in the assembled body, including the instructions message.
+    pub fn message_count(&self) -> usize {
+        self.body
+            .get("messages")
+            .and_then(Value::as_array)
+            .map_or(0, Vec::len)
+    }
+
+    /// Cheap, monotonic token estimate for the assembled messages using a ~4 characters per
+    /// token heuristic. Not exact; intended for client-side history trimming.
+    pub fn estimate_tokens(&self) -> usize {
+        self.estimate_tokens_with(|text| text.chars().count().div_ceil(4))
+    }
+
+    /// Same as [`Self::estimate_tokens`] but counts each text fragment with `count_tokens`, e.g.
+    /// a real BPE tokenizer. Image URLs are not counted.
+    pub fn estimate_tokens_with<F>(&self, count_tokens: F) -> usize
+    where
+        F: Fn(&str) -> usize,
+    {
+        self.body
+            .get("messages")
+            .and_then(Value::as_array)
+            .map_or(0, |messages| {
+                messages
+                    .iter()
+                    .map(|message| {
+                        MESSAGE_TOKEN_OVERHEAD + count_value_tokens(message, &count_tokens)
+                    })
+                    .sum()
+            })
+    }
+}
+
+fn redact_image_data_urls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                if key == "image_url"
+                    && let Some(Value::String(url)) = child.get_mut("url")
+                    && url.starts_with("data:")
+                {
+                    let bytes = data_uri_decoded_len(url)
+                        .unwrap_or_else(|| url.split_once(',').map_or(0, |(_, data)| data.len()));
+                    *url = format!("<image:{bytes} bytes>");
+                } else {
+                    redact_image_data_urls(child);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_image_data_urls),
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => {}
+    }
+}
+
+fn count_value_tokens(value: &Value, count_tokens: &dyn Fn(&str) -> usize) -> usize {
+    match value {
+        Value::String(text) => count_tokens(text),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| count_value_tokens(item, count_tokens))
+            .sum(),
+        Value::Object(map) => map
+            .iter()
+            .filter(|(key, _)| key.as_str() != "image_url")
+            .map(|(_, value)| count_value_tokens(value, count_tokens))
+            .sum(),
+        Value::Null | Value::Bool(_) | Value::Number(_) => 0,
+    }
+}
+
+/// Controls whether and how the model may call tools for a single request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToolChoice {
+    Auto,
+    None,
+    Required,
+    /// Force a call to the named function.
+    Function(String),
+}
+
+impl ToolChoice {
+    fn to_value(&self) -> Value {
+        match self {
+            ToolChoice::Auto => json!("auto"),
+            ToolChoice::None => json!("none"),
+            ToolChoice::Required => json!("required"),
+            ToolChoice::Function(name) => json!({
+                "type": "function",
+                "function": {"name": name},
+            }),
+        }
+    }
+}
+
+/// Requested reasoning effort, emitted as `reasoning.effort` when reasoning is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+impl ReasoningEffort {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReasoningEffort::Low => "low",
+            ReasoningEffort::Medium => "medium",
+            ReasoningEffort::High => "high",
+        }
+    }
+}
+
+/// Role used for the leading instructions message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InstructionsRole {
+    #[default]
+    System,
+    Developer,
+}
+
+impl InstructionsRole {
+    fn as_str(self) -> &'static str {
+        match self {
+            InstructionsRole::System => "system",
+            InstructionsRole::Developer => "developer",
+        }
+    }
+}
+
+/// Structured output mode requested via `response_format`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    /// The schema is forwarded exactly as provided.
+    JsonSchema {
+        name: String,
+        schema: Value,
+        strict: bool,
+    },
+}
+
+impl ResponseFormat {
+    fn to_value(&self) -> Value {
+        match self {
+            ResponseFormat::Text => json!({"type": "text"}),
+            ResponseFormat::JsonObject => json!({"type": "json_object"}),
+            ResponseFormat::JsonSchema {
+                name,
+                schema,
+                strict,
+            } => json!({
+                "type": "json_schema",
+                "json_schema": {
+                    "name": name,
+                    "schema": schema,
+                    "strict": strict,
+                },
+            }),
+        }
+    }
+}
+
+/// Where the instructions message is placed in the assembled message list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InstructionsPlacement {
+    #[default]
+    First,
+    /// For chat templates that expect instructions after the conversation.
+    Last,
+}
+
+/// How structured tool outputs (`content_items`) are serialized into tool messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ToolOutputFormat {
+    /// An array of `text` / `image_url` parts. An empty `content_items` list is sent as an
+    /// empty string instead, or a single empty text part under `force_structured_content`,
+    /// since some providers reject tool messages with `"content": []`.
+    #[default]
+    Structured,
+    /// A single string joining the text parts with newlines; images become a placeholder.
+    FlatText,
+}
+
+/// A function tool definition, serialized into the standard Chat Completions tool shape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolDef {
+    pub name: String,
+    pub description: String,
+    /// JSON schema for the function arguments.
+    pub parameters: Value,
+}
+
+impl ToolDef {
+    fn to_value(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters,
+            },
+        })
+    }
+}
+
+/// Processing tier requested via `service_tier`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceTier {
+    Auto,
+    Default,
+    Flex,
+    Priority,
+}
+
+impl ServiceTier {
+    fn as_str(self) -> &'static str {
+        match self {
+            ServiceTier::Auto => "auto",
+            ServiceTier::Default => "default",
+            ServiceTier::Flex => "flex",
+            ServiceTier::Priority => "priority",
+        }
+    }
+}
+
+/// Output modality requested via `modalities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modality {
+    Text,
+    Audio,
+}
+
+impl Modality {
+    fn as_str(self) -> &'static str {
+        match self {
+            Modality::Text => "text",
+            Modality::Audio => "audio",
+        }
+    }
+}
+
+/// Spoken output settings, emitted as the `audio` object. Requires [`Modality::Audio`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioConfig {
+    pub voice: String,
+    /// Output encoding, e.g. `wav` or `mp3`.
+    pub format: String,
+}
+
+/// Field used to replay reasoning on assistant messages, which varies between providers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReasoningFieldStyle {
+    /// `"reasoning": text`
+    #[default]
+    Default,
+    /// `"reasoning_content": text`, as used by DeepSeek-style APIs.
+    ReasoningContent,
+    /// `"thinking": text`
+    ThinkingBlock,
+}
+
+impl ReasoningFieldStyle {
+    fn field_name(self) -> &'static str {
+        match self {
+            ReasoningFieldStyle::Default => "reasoning",
+            ReasoningFieldStyle::ReasoningContent => "reasoning_content",
+            ReasoningFieldStyle::ThinkingBlock => "thinking",
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct ChatRequestBuilder<'a> {
-    model: &'a str,
-    instructions: &'a str,
-    input: &'a [ResponseItem],
-    tools: &'a [Value],
+    model: Cow<'a, str>,
+    instructions: Cow<'a, str>,
+    input: Cow<'a, [ResponseItem]>,
+    tools: Cow<'a, [Value]>,
     conversation_id: Option<String>,
     session_source: Option<SessionSource>,
+    max_completion_tokens: Option<u32>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    stop_sequences: Vec<String>,
+    tool_choice: Option<ToolChoice>,
+    stream: bool,
+    enable_reasoning: bool,
+    reasoning_effort: Option<ReasoningEffort>,
+    instructions_role: InstructionsRole,
+    drop_reasoning: bool,
+    parallel_tool_calls: Option<bool>,
+    response_format: Option<ResponseFormat>,
+    extra_body: Map<String, Value>,
+    extra_headers: HeaderMap,
+    user_tag: Option<String>,
+    cache_instructions: bool,
+    seed: Option<u64>,
+    logprobs: bool,
+    top_logprobs: Option<u8>,
+    max_messages: Option<usize>,
+    instructions_placement: InstructionsPlacement,
+    frequency_penalty: Option<f64>,
+    presence_penalty: Option<f64>,
+    additional_instructions: Vec<String>,
+    strict: bool,
+    metadata: HashMap<String, String>,
+    n: Option<u32>,
+    tool_output_format: ToolOutputFormat,
+    provider_routing: Option<Value>,
+    include_usage: bool,
+    reasoning_field_style: ReasoningFieldStyle,
+    message_transform: Option<Arc<dyn Fn(&mut Value) + Send + Sync>>,
+    logit_bias: HashMap<u32, f64>,
+    max_history_bytes: Option<usize>,
+    emit_web_search: bool,
+    assistant_prefill: Option<String>,
+    tool_output_autodetect_images: bool,
+    reasoning_as_content_block: bool,
+    omit_system_message: bool,
+    reasoning_join: Option<&'a str>,
+    stream_options: Map<String, Value>,
+    subagent_header_name: &'a str,
+    service_tier: Option<ServiceTier>,
+    prediction: Option<String>,
+    cache_up_to_last_user: bool,
+    max_images: Option<usize>,
+    force_structured_content: bool,
+    store: Option<bool>,
+    tool_name_rewrite: Option<Arc<dyn Fn(&str) -> String + Send + Sync>>,
+    max_instructions_bytes: Option<usize>,
+    function_arguments_as_object: bool,
+    omit_empty_tools: bool,
+    reasoning_max_tokens: Option<u32>,
+    clear_thinking: Option<bool>,
+    chat_template_kwargs: Map<String, Value>,
+    beta_features: Vec<String>,
+    strict_tools: bool,
+    modalities: Vec<Modality>,
+    audio_output: Option<AudioConfig>,
+    max_tools: Option<usize>,
+    dedup_assistant_window: usize,
+    include_ghost_snapshots: bool,
+    prompt_cache_key: Option<String>,
+    template_vars: HashMap<String, String>,
+    safety_identifier: Option<String>,
+    tools_unsupported: bool,
+    max_image_bytes: Option<usize>,
+    mark_tool_errors: bool,
 }
 
 impl<'a> ChatRequestBuilder<'a> {
@@ -34,6 +421,20 @@ impl<'a> ChatRequestBuilder<'a> {
         instructions: &'a str,
         input: &'a [ResponseItem],
         tools: &'a [Value],
+    ) -> Self {
+        Self::from_parts(
+            Cow::Borrowed(model),
+            Cow::Borrowed(instructions),
+            Cow::Borrowed(input),
+            Cow::Borrowed(tools),
+        )
+    }
+
+    fn from_parts(
+        model: Cow<'a, str>,
+        instructions: Cow<'a, str>,
+        input: Cow<'a, [ResponseItem]>,
+        tools: Cow<'a, [Value]>,
     ) -> Self {
         Self {
             model,
@@ -42,9 +443,111 @@ impl<'a> ChatRequestBuilder<'a> {
             tools,
             conversation_id: None,
             session_source: None,
+            max_completion_tokens: None,
+            temperature: None,
+            top_p: None,
+            stop_sequences: Vec::new(),
+            tool_choice: None,
+            stream: true,
+            enable_reasoning: false,
+            reasoning_effort: None,
+            instructions_role: InstructionsRole::default(),
+            drop_reasoning: false,
+            parallel_tool_calls: None,
+            response_format: None,
+            extra_body: Map::new(),
+            extra_headers: HeaderMap::new(),
+            user_tag: None,
+            cache_instructions: false,
+            seed: None,
+            logprobs: false,
+            top_logprobs: None,
+            max_messages: None,
+            instructions_placement: InstructionsPlacement::default(),
+            frequency_penalty: None,
+            presence_penalty: None,
+            additional_instructions: Vec::new(),
+            strict: false,
+            metadata: HashMap::new(),
+            n: None,
+            tool_output_format: ToolOutputFormat::default(),
+            provider_routing: None,
+            include_usage: false,
+            reasoning_field_style: ReasoningFieldStyle::default(),
+            message_transform: None,
+            logit_bias: HashMap::new(),
+            max_history_bytes: None,
+            emit_web_search: false,
+            assistant_prefill: None,
+            tool_output_autodetect_images: false,
+            reasoning_as_content_block: false,
+            omit_system_message: false,
+            reasoning_join: None,
+            stream_options: Map::new(),
+            subagent_header_name: DEFAULT_SUBAGENT_HEADER,
+            service_tier: None,
+            prediction: None,
+            cache_up_to_last_user: false,
+            max_images: None,
+            force_structured_content: false,
+            store: None,
+            tool_name_rewrite: None,
+            max_instructions_bytes: None,
+            function_arguments_as_object: false,
+            omit_empty_tools: false,
+            reasoning_max_tokens: None,
+            clear_thinking: None,
+            chat_template_kwargs: Map::new(),
+            beta_features: Vec::new(),
+            strict_tools: false,
+            modalities: Vec::new(),
+            audio_output: None,
+            max_tools: None,
+            dedup_assistant_window: 1,
+            include_ghost_snapshots: false,
+            prompt_cache_key: None,
+            template_vars: HashMap::new(),
+            safety_identifier: None,
+            tools_unsupported: false,
+            max_image_bytes: None,
+            mark_tool_errors: false,
         }
     }
 
+    /// Owning counterpart of [`Self::new`] for callers that produce the prompt inline and
+    /// cannot keep it alive until `build()`.
+    pub fn new_owned(
+        model: String,
+        instructions: String,
+        input: Vec<ResponseItem>,
+        tools: Vec<Value>,
+        enable_reasoning: bool,
+    ) -> ChatRequestBuilder<'static> {
+        ChatRequestBuilder::from_parts(
+            Cow::Owned(model),
+            Cow::Owned(instructions),
+            Cow::Owned(input),
+            Cow::Owned(tools),
+        )
+        .enable_reasoning(enable_reasoning)
+    }
+
+    /// Copy of this builder targeting `model`, with every other setting preserved. Useful for
+    /// fanning the same prompt out to several models.
+    pub fn with_model(&self, model: &'a str) -> ChatRequestBuilder<'a> {
+        let mut builder = self.clone();
+        builder.model = Cow::Borrowed(model);
+        builder
+    }
+
+    /// Appends typed function tools after any raw `tools` passed to the constructor.
+    pub fn tool_defs(mut self, defs: Vec<ToolDef>) -> Self {
+        self.tools
+            .to_mut()
+            .extend(defs.iter().map(ToolDef::to_value));
+        self
+    }
+
     pub fn conversation_id(mut self, id: Option<String>) -> Self {
         self.conversation_id = id;
         self
@@ -55,438 +558,5300 @@ impl<'a> ChatRequestBuilder<'a> {
         self
     }
 
-    pub fn build(self, _provider: &Provider) -> Result<ChatRequest, ApiError> {
-        let mut messages = Vec::<Value>::new();
-        messages.push(json!({"role": "system", "content": self.instructions}));
+    pub fn max_completion_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.max_completion_tokens = max_tokens;
+        self
+    }
 
-        let input = self.input;
-        let mut reasoning_by_anchor_index: HashMap<usize, String> = HashMap::new();
-        let mut last_emitted_role: Option<&str> = None;
-        for item in input {
-            match item {
-                ResponseItem::Message { role, .. } => last_emitted_role = Some(role.as_str()),
-                ResponseItem::FunctionCall { .. } | ResponseItem::LocalShellCall { .. } => {
-                    last_emitted_role = Some("assistant")
-                }
-                ResponseItem::FunctionCallOutput { .. } => last_emitted_role = Some("tool"),
-                ResponseItem::Reasoning { .. } | ResponseItem::Other => {}
-                ResponseItem::CustomToolCall { .. } => {}
-                ResponseItem::CustomToolCallOutput { .. } => {}
-                ResponseItem::WebSearchCall { .. } => {}
-                ResponseItem::GhostSnapshot { .. } => {}
-                ResponseItem::Compaction { .. } => {}
-            }
-        }
+    pub fn temperature(mut self, temperature: Option<f64>) -> Self {
+        self.temperature = temperature;
+        self
+    }
 
-        let mut last_user_index: Option<usize> = None;
-        for (idx, item) in input.iter().enumerate() {
-            if let ResponseItem::Message { role, .. } = item
-                && role == "user"
-            {
-                last_user_index = Some(idx);
-            }
-        }
+    pub fn top_p(mut self, top_p: Option<f64>) -> Self {
+        self.top_p = top_p;
+        self
+    }
 
-        if !matches!(last_emitted_role, Some("user")) {
-            for (idx, item) in input.iter().enumerate() {
-                if let Some(u_idx) = last_user_index
-                    && idx <= u_idx
-                {
-                    continue;
-                }
+    pub fn stop_sequences(mut self, stop: Vec<String>) -> Self {
+        self.stop_sequences = stop;
+        self
+    }
 
-                if let ResponseItem::Reasoning {
-                    content: Some(items),
-                    ..
-                } = item
-                {
-                    let mut text = String::new();
-                    for entry in items {
-                        match entry {
-                            ReasoningItemContent::ReasoningText { text: segment }
-                            | ReasoningItemContent::Text { text: segment } => {
-                                text.push_str(segment)
-                            }
-                        }
-                    }
-                    if text.trim().is_empty() {
-                        continue;
-                    }
+    pub fn tool_choice(mut self, choice: ToolChoice) -> Self {
+        self.tool_choice = Some(choice);
+        self
+    }
 
-                    let mut attached = false;
-                    if idx > 0
-                        && let ResponseItem::Message { role, .. } = &input[idx - 1]
-                        && role == "assistant"
-                    {
-                        reasoning_by_anchor_index
-                            .entry(idx - 1)
-                            .and_modify(|v| v.push_str(&text))
-                            .or_insert(text.clone());
-                        attached = true;
-                    }
+    /// Defaults to `true`; disable for callers that need a single-shot completion.
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
 
-                    if !attached && idx + 1 < input.len() {
-                        match &input[idx + 1] {
-                            ResponseItem::FunctionCall { .. }
-                            | ResponseItem::LocalShellCall { .. } => {
-                                reasoning_by_anchor_index
-                                    .entry(idx + 1)
-                                    .and_modify(|v| v.push_str(&text))
-                                    .or_insert(text.clone());
-                            }
-                            ResponseItem::Message { role, .. } if role == "assistant" => {
-                                reasoning_by_anchor_index
-                                    .entry(idx + 1)
-                                    .and_modify(|v| v.push_str(&text))
-                                    .or_insert(text.clone());
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            }
-        }
+    /// Ask the provider to reason before answering by emitting a top-level `reasoning` object.
+    pub fn enable_reasoning(mut self, enabled: bool) -> Self {
+        self.enable_reasoning = enabled;
+        self
+    }
 
-        let mut last_assistant_text: Option<String> = None;
+    /// Only takes effect when reasoning is enabled; otherwise no controls are emitted.
+    pub fn reasoning_effort(mut self, effort: ReasoningEffort) -> Self {
+        self.reasoning_effort = Some(effort);
+        self
+    }
 
-        for (idx, item) in input.iter().enumerate() {
-            match item {
-                ResponseItem::Message { role, content, .. } => {
-                    let mut text = String::new();
-                    let mut items: Vec<Value> = Vec::new();
-                    let mut saw_image = false;
+    pub fn instructions_role(mut self, role: InstructionsRole) -> Self {
+        self.instructions_role = role;
+        self
+    }
 
-                    for c in content {
-                        match c {
-                            ContentItem::InputText { text: t }
-                            | ContentItem::OutputText { text: t } => {
-                                text.push_str(t);
-                                items.push(json!({"type":"text","text": t}));
-                            }
-                            ContentItem::InputImage { image_url } => {
-                                saw_image = true;
-                                items.push(
-                                    json!({"type":"image_url","image_url": {"url": image_url}}),
-                                );
-                            }
-                        }
-                    }
+    /// Strip every reasoning field from the request, for providers that reject them.
+    ///
+    /// This takes precedence over [`Self::enable_reasoning`]: no reasoning text is attached to
+    /// replayed assistant turns and no reasoning controls are emitted, regardless of roles.
+    pub fn drop_reasoning(mut self, drop: bool) -> Self {
+        self.drop_reasoning = drop;
+        self
+    }
 
-                    if role == "assistant" {
-                        if let Some(prev) = &last_assistant_text
-                            && prev == &text
-                        {
-                            continue;
-                        }
-                        last_assistant_text = Some(text.clone());
-                    }
+    /// Only emitted when the request carries tools, since providers reject it otherwise.
+    pub fn parallel_tool_calls(mut self, enabled: Option<bool>) -> Self {
+        self.parallel_tool_calls = enabled;
+        self
+    }
 
-                    let content_value = if role == "assistant" {
-                        json!(text)
-                    } else if saw_image {
-                        json!(items)
-                    } else {
-                        json!(text)
-                    };
+    pub fn response_format(mut self, format: ResponseFormat) -> Self {
+        self.response_format = Some(format);
+        self
+    }
 
-                    let mut msg = json!({"role": role, "content": content_value});
-                    if role == "assistant"
-                        && let Some(reasoning) = reasoning_by_anchor_index.get(&idx)
-                        && let Some(obj) = msg.as_object_mut()
-                    {
-                        obj.insert("reasoning".to_string(), json!(reasoning));
-                    }
-                    messages.push(msg);
-                }
-                ResponseItem::FunctionCall {
-                    name,
-                    arguments,
-                    call_id,
-                    ..
-                } => {
-                    let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
-                    let tool_call = json!({
-                        "id": call_id,
-                        "type": "function",
-                        "function": {
-                            "name": name,
-                            "arguments": arguments,
-                        }
-                    });
-                    push_tool_call_message(&mut messages, tool_call, reasoning);
-                }
-                ResponseItem::LocalShellCall {
-                    id,
-                    call_id: _,
-                    status,
-                    action,
-                } => {
-                    let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
-                    let tool_call = json!({
-                        "id": id.clone().unwrap_or_default(),
-                        "type": "local_shell_call",
-                        "status": status,
-                        "action": action,
-                    });
-                    push_tool_call_message(&mut messages, tool_call, reasoning);
-                }
-                ResponseItem::FunctionCallOutput { call_id, output } => {
-                    let content_value = if let Some(items) = &output.content_items {
-                        let mapped: Vec<Value> = items
-                            .iter()
-                            .map(|it| match it {
-                                FunctionCallOutputContentItem::InputText { text } => {
-                                    json!({"type":"text","text": text})
-                                }
-                                FunctionCallOutputContentItem::InputImage { image_url } => {
-                                    json!({"type":"image_url","image_url": {"url": image_url}})
-                                }
-                            })
-                            .collect();
-                        json!(mapped)
-                    } else {
-                        json!(output.content)
-                    };
+    /// Provider-specific fields merged into the body after all standard fields, sorted by key
+    /// so the serialized payload has a stable order.
+    ///
+    /// On key collisions the extra field wins (keeping the standard field's position), so this
+    /// can also override values set by the typed builder methods.
+    pub fn extra_body(mut self, extra: Map<String, Value>) -> Self {
+        self.extra_body = extra;
+        self
+    }
 
-                    messages.push(json!({
-                        "role": "tool",
-                        "tool_call_id": call_id,
-                        "content": content_value,
-                    }));
-                }
-                ResponseItem::CustomToolCall {
-                    id,
-                    call_id: _,
-                    name,
-                    input,
-                    status: _,
-                } => {
-                    let tool_call = json!({
-                        "id": id,
-                        "type": "custom",
-                        "custom": {
-                            "name": name,
-                            "input": input,
-                        }
-                    });
-                    let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
-                    push_tool_call_message(&mut messages, tool_call, reasoning);
+    /// Headers merged after the conversation headers, so these win on collisions.
+    pub fn extra_headers(mut self, headers: HeaderMap) -> Self {
+        self.extra_headers = headers;
+        self
+    }
+
+    /// Tags the request with a tenant/user identifier, sent both as the `x-codex-user` header
+    /// and as the body's `user` field. Must be non-empty ASCII.
+    pub fn user_tag(mut self, tag: Option<String>) -> Self {
+        self.user_tag = tag;
+        self
+    }
+
+    /// Marks the instructions as an Anthropic-style `cache_control` breakpoint. The content is
+    /// then sent as a structured text part instead of a bare string.
+    pub fn cache_instructions(mut self, enabled: bool) -> Self {
+        self.cache_instructions = enabled;
+        self
+    }
+
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn logprobs(mut self, enabled: bool) -> Self {
+        self.logprobs = enabled;
+        self
+    }
+
+    /// Setting this implies `logprobs: true`. Must be at most 20.
+    pub fn top_logprobs(mut self, top: Option<u8>) -> Self {
+        self.top_logprobs = top;
+        self
+    }
+
+    /// Fails the build with [`ApiError::HistoryTooLong`] when the assembled message list,
+    /// counted after dedup and coalescing, exceeds `limit`.
+    pub fn max_messages(mut self, limit: Option<usize>) -> Self {
+        self.max_messages = limit;
+        self
+    }
+
+    pub fn instructions_placement(mut self, placement: InstructionsPlacement) -> Self {
+        self.instructions_placement = placement;
+        self
+    }
+
+    /// Must be within `-2.0..=2.0`.
+    pub fn frequency_penalty(mut self, penalty: Option<f64>) -> Self {
+        self.frequency_penalty = penalty;
+        self
+    }
+
+    /// Must be within `-2.0..=2.0`.
+    pub fn presence_penalty(mut self, penalty: Option<f64>) -> Self {
+        self.presence_penalty = penalty;
+        self
+    }
+
+    /// Extra instruction fragments, each emitted as its own message right after the primary
+    /// instructions and with the same role. Empty fragments are skipped.
+    pub fn additional_instructions(mut self, fragments: Vec<String>) -> Self {
+        self.additional_instructions = fragments;
+        self
+    }
+
+    /// Opt into validating the history for corruption (e.g. tool outputs that reference a call
+    /// id never seen earlier) instead of forwarding it to the provider as-is.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Request-level key/value metadata that some providers store alongside their logs. Keys
+    /// must be non-empty and values at most 512 characters.
+    pub fn metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Number of completions to request. Only emitted when greater than one, which requires
+    /// streaming to be disabled.
+    pub fn n(mut self, n: Option<u32>) -> Self {
+        self.n = n;
+        self
+    }
+
+    pub fn tool_output_format(mut self, format: ToolOutputFormat) -> Self {
+        self.tool_output_format = format;
+        self
+    }
+
+    /// Routing preferences for aggregators such as OpenRouter, emitted verbatim under the
+    /// `provider` key. Must be a JSON object.
+    pub fn provider_routing(mut self, routing: Value) -> Self {
+        self.provider_routing = Some(routing);
+        self
+    }
+
+    /// Requests a final usage chunk via `stream_options.include_usage`. No-op when streaming is
+    /// disabled.
+    pub fn include_usage(mut self, enabled: bool) -> Self {
+        self.include_usage = enabled;
+        self
+    }
+
+    pub fn reasoning_field_style(mut self, style: ReasoningFieldStyle) -> Self {
+        self.reasoning_field_style = style;
+        self
+    }
+
+    /// Hook invoked on every assembled message, in emission order, so callers can rewrite
+    /// messages in place (e.g. truncate tool output or redact secrets). It runs after reasoning
+    /// attachment, tool-call grouping and user-message merging, so it sees each message in its
+    /// final shape.
+    pub fn message_transform(mut self, transform: Box<dyn Fn(&mut Value) + Send + Sync>) -> Self {
+        self.message_transform = Some(Arc::from(transform));
+        self
+    }
+
+    /// Per-token biases keyed by token id. Values are clamped to `-100.0..=100.0`; an empty map
+    /// omits `logit_bias` from the payload.
+    pub fn logit_bias(mut self, bias: HashMap<u32, f64>) -> Self {
+        self.logit_bias = bias;
+        self
+    }
+
+    /// Byte budget for the serialized messages. When exceeded, the oldest turns are dropped
+    /// until the history fits; an assistant tool-call message is dropped together with its tool
+    /// replies. System messages, instructions and the final user message are always kept. The
+    /// assistant prefill and cache breakpoint are applied after trimming and never dropped.
+    pub fn max_history_bytes(mut self, limit: Option<usize>) -> Self {
+        self.max_history_bytes = limit;
+        self
+    }
+
+    /// Replays `WebSearchCall` items as assistant `tool_calls` entries of type `web_search`
+    /// instead of dropping them. Only for providers that accept that shape.
+    pub fn emit_web_search(mut self, enabled: bool) -> Self {
+        self.emit_web_search = enabled;
+        self
+    }
+
+    /// Text the model must continue from. It is appended to the history as a final assistant
+    /// message marked `"partial": true`, or appended to the last message when that is already
+    /// an assistant message with content.
+    pub fn assistant_prefill(mut self, prefill: Option<String>) -> Self {
+        self.assistant_prefill = prefill;
+        self
+    }
+
+    /// Sends a plain-string tool output that is just an image URL (an image data URI or an
+    /// http(s) URL ending in an image extension) as a structured `image_url` part.
+    pub fn tool_output_autodetect_images(mut self, enabled: bool) -> Self {
+        self.tool_output_autodetect_images = enabled;
+        self
+    }
+
+    /// Replays reasoning as a `{"type": "reasoning", "text": ...}` part at the start of the
+    /// assistant message's `content` array instead of a sibling field. Overrides
+    /// [`Self::reasoning_field_style`].
+    pub fn reasoning_as_content_block(mut self, enabled: bool) -> Self {
+        self.reasoning_as_content_block = enabled;
+        self
+    }
+
+    /// Skips the instructions message entirely, for fine-tuned models that bake in their own
+    /// system prompt. The `instructions` argument is ignored; fragments passed to
+    /// [`Self::additional_instructions`] are still emitted.
+    pub fn omit_system_message(mut self, omit: bool) -> Self {
+        self.omit_system_message = omit;
+        self
+    }
+
+    /// Separator used when concatenating reasoning text, both between the segments of one
+    /// reasoning item and between reasoning items attached to the same message. When unset,
+    /// segments are joined directly and items are separated by a newline.
+    pub fn reasoning_join(mut self, separator: &'a str) -> Self {
+        self.reasoning_join = Some(separator);
+        self
+    }
+
+    /// Adds an arbitrary key to `stream_options`, e.g. a gateway-specific chunk size hint.
+    /// Composes with [`Self::include_usage`] and is a no-op when streaming is disabled.
+    pub fn stream_option(mut self, key: impl Into<String>, value: Value) -> Self {
+        self.stream_options.insert(key.into(), value);
+        self
+    }
+
+    /// Header that carries the subagent label, for gateways that expect a different name than
+    /// `x-openai-subagent`.
+    pub fn subagent_header_name(mut self, name: &'a str) -> Self {
+        self.subagent_header_name = name;
+        self
+    }
+
+    pub fn service_tier(mut self, tier: ServiceTier) -> Self {
+        self.service_tier = Some(tier);
+        self
+    }
+
+    /// Predicted output for speculative decoding, e.g. the current contents of a file being
+    /// edited.
+    pub fn prediction(mut self, predicted: Option<String>) -> Self {
+        self.prediction = predicted;
+        self
+    }
+
+    /// Marks the message right before the final user message with an ephemeral
+    /// `cache_control` breakpoint so the whole stable prefix is cached. That message's content
+    /// is sent as a parts array.
+    pub fn cache_up_to_last_user(mut self, enabled: bool) -> Self {
+        self.cache_up_to_last_user = enabled;
+        self
+    }
+
+    /// Upper bound on image parts across all messages, including tool outputs.
+    pub fn max_images(mut self, limit: Option<usize>) -> Self {
+        self.max_images = limit;
+        self
+    }
+
+    /// Always sends user and plain-text tool message content as an array of `text` parts,
+    /// for providers that reject the bare string shortcut.
+    pub fn force_structured_content(mut self, enabled: bool) -> Self {
+        self.force_structured_content = enabled;
+        self
+    }
+
+    /// Server-side retention of the completion. Omitted when unset so the provider default
+    /// applies.
+    pub fn store(mut self, store: Option<bool>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Rewrites tool names, e.g. to namespace them per server when bridging providers. It is
+    /// applied to `function.name` of every function tool definition, to the name of every
+    /// replayed `FunctionCall`, and to a [`ToolChoice::Function`] choice, so all three stay
+    /// consistent.
+    pub fn tool_name_rewrite(mut self, rewrite: Box<dyn Fn(&str) -> String + Send + Sync>) -> Self {
+        self.tool_name_rewrite = Some(Arc::from(rewrite));
+        self
+    }
+
+    /// Upper bound on the emitted instructions (the primary instructions plus any additional
+    /// fragments), in bytes.
+    pub fn max_instructions_bytes(mut self, limit: Option<usize>) -> Self {
+        self.max_instructions_bytes = limit;
+        self
+    }
+
+    /// Emits replayed function call `arguments` as a JSON object rather than a string when
+    /// they parse as one. Anything else is sent as the original string.
+    pub fn function_arguments_as_object(mut self, enabled: bool) -> Self {
+        self.function_arguments_as_object = enabled;
+        self
+    }
+
+    /// Leaves out `tools` (and `tool_choice`) instead of sending `"tools": []` when there are
+    /// no tools, for providers that reject an empty array.
+    pub fn omit_empty_tools(mut self, omit: bool) -> Self {
+        self.omit_empty_tools = omit;
+        self
+    }
+
+    /// Reasoning token budget, emitted as `reasoning.max_tokens`. Ignored unless reasoning is
+    /// enabled.
+    pub fn reasoning_max_tokens(mut self, max_tokens: Option<u32>) -> Self {
+        self.reasoning_max_tokens = max_tokens;
+        self
+    }
+
+    /// Emits a GLM-style `"thinking": {"type": "enabled", "clear_thinking": ...}` control
+    /// alongside `reasoning`; `true` asks the provider to drop earlier thinking from context.
+    /// Ignored unless reasoning is enabled.
+    pub fn clear_thinking(mut self, clear: bool) -> Self {
+        self.clear_thinking = Some(clear);
+        self
+    }
+
+    /// Keyword arguments for self-hosted chat templates, emitted as `chat_template_kwargs`.
+    /// With reasoning enabled they are merged over a default `"thinking": true`; otherwise
+    /// they are sent as given. An empty map omits the field.
+    pub fn chat_template_kwargs(mut self, kwargs: Map<String, Value>) -> Self {
+        self.chat_template_kwargs = kwargs;
+        self
+    }
+
+    /// Beta features sent as a single comma-separated `OpenAI-Beta` header.
+    pub fn beta_features(mut self, features: Vec<String>) -> Self {
+        self.beta_features = features;
+        self
+    }
+
+    /// Opts every function tool into strict structured calling by setting `function.strict`
+    /// to `true` and `additionalProperties` to `false` on its parameters schema. Values a tool
+    /// already sets are left alone.
+    pub fn strict_tools(mut self, enabled: bool) -> Self {
+        self.strict_tools = enabled;
+        self
+    }
+
+    /// Output modalities, e.g. text plus audio. An empty list omits the field.
+    pub fn modalities(mut self, modalities: Vec<Modality>) -> Self {
+        self.modalities = modalities;
+        self
+    }
+
+    /// Voice and format for audio output. Only valid when `modalities` includes
+    /// [`Modality::Audio`].
+    pub fn audio_output(mut self, config: Option<AudioConfig>) -> Self {
+        self.audio_output = config;
+        self
+    }
+
+    /// Upper bound on the number of tool definitions sent with the request.
+    pub fn max_tools(mut self, limit: Option<usize>) -> Self {
+        self.max_tools = limit;
+        self
+    }
+
+    /// Number of preceding assistant texts a new assistant message is compared against
+    /// before being dropped as a duplicate. Defaults to 1; 0 disables the dedup.
+    pub fn dedup_assistant_window(mut self, window: usize) -> Self {
+        self.dedup_assistant_window = window;
+        self
+    }
+
+    /// Emits each `GhostSnapshot` item as a short system note, in place, so the model knows
+    /// where workspace snapshots were taken. Off by default, which skips them.
+    pub fn include_ghost_snapshots(mut self, enabled: bool) -> Self {
+        self.include_ghost_snapshots = enabled;
+        self
+    }
+
+    /// Explicit `prompt_cache_key` so requests sharing a prefix land on the same cache. Must
+    /// be non-empty.
+    pub fn prompt_cache_key(mut self, key: Option<String>) -> Self {
+        self.prompt_cache_key = key;
+        self
+    }
+
+    /// Values substituted for `{{key}}` placeholders in the instructions at build time.
+    /// Unknown placeholders are left untouched, or rejected in strict mode.
+    pub fn template_vars(mut self, vars: HashMap<String, String>) -> Self {
+        self.template_vars = vars;
+        self
+    }
+
+    /// Hashed end-user identifier for abuse monitoring, sent both as the body's
+    /// `safety_identifier` field and as the `x-safety-identifier` header. Must be non-empty hex.
+    pub fn safety_identifier(mut self, identifier: Option<String>) -> Self {
+        self.safety_identifier = identifier;
+        self
+    }
+
+    /// For models without tool support: replays each tool call as assistant text
+    /// (`Called name (call_id) with arguments`) and each tool output as a user message labelled
+    /// with its call, and omits `tools` and `tool_choice` from the request.
+    pub fn tools_unsupported(mut self, enabled: bool) -> Self {
+        self.tools_unsupported = enabled;
+        self
+    }
+
+    /// Upper bound on the decoded size of each inline `data:` image. Remote http(s) images are
+    /// not checked.
+    pub fn max_image_bytes(mut self, limit: Option<usize>) -> Self {
+        self.max_image_bytes = limit;
+        self
+    }
+
+    /// Adds `"is_error": true` to tool messages whose output reports `success: false`, for
+    /// providers that accept the flag on tool content.
+    pub fn mark_tool_errors(mut self, enabled: bool) -> Self {
+        self.mark_tool_errors = enabled;
+        self
+    }
+
+    pub fn build(self, _provider: &Provider) -> Result<ChatRequest, ApiError> {
+        let body = self.build_body()?;
+
+        let mut headers = build_conversation_headers(self.conversation_id);
+        if let Some(subagent) = subagent_header(&self.session_source) {
+            insert_header(&mut headers, self.subagent_header_name, &subagent);
+        }
+        if let Some(tag) = &self.user_tag {
+            insert_header(&mut headers, "x-codex-user", tag);
+        }
+        if let Some(identifier) = &self.safety_identifier {
+            insert_header(&mut headers, "x-safety-identifier", identifier);
+        }
+        if !self.beta_features.is_empty() {
+            insert_header(&mut headers, "OpenAI-Beta", &self.beta_features.join(","));
+        }
+        headers.extend(self.extra_headers);
+
+        let tool_call_ids = emitted_tool_call_ids(&body);
+        Ok(ChatRequest {
+            body,
+            headers,
+            tool_call_ids,
+        })
+    }
+
+    /// One-line description of the builder's configuration for logging. Only counts are
+    /// reported, never message contents.
+    pub fn summary(&self) -> String {
+        format!(
+            "model={} input_items={} tools={} reasoning={}",
+            self.model,
+            self.input.len(),
+            self.tools.len(),
+            self.enable_reasoning,
+        )
+    }
+
+    /// Assembles only the request body, skipping header construction entirely. Useful for
+    /// snapshotting prompt assembly without conversation state.
+    pub fn build_body_only(self) -> Result<Value, ApiError> {
+        self.build_body()
+    }
+
+    fn build_body(&self) -> Result<Value, ApiError> {
+        if let Some(limit) = self.max_tools
+            && self.tools.len() > limit
+        {
+            return Err(ApiError::TooManyTools {
+                count: self.tools.len(),
+                limit,
+            });
+        }
+        validate_tools(&self.tools)?;
+        if self.strict {
+            if self.input.is_empty() {
+                return Err(ApiError::EmptyInput);
+            }
+            validate_tool_call_ids(&self.input)?;
+            validate_tool_calls_resolved(&self.input)?;
+            for url in image_urls(&self.input, false) {
+                validate_image_url(url)?;
+            }
+        }
+        validate_metadata(&self.metadata)?;
+        if self.strict
+            && !self.enable_reasoning
+            && (self.reasoning_effort.is_some()
+                || self.reasoning_max_tokens.is_some()
+                || self.clear_thinking.is_some())
+        {
+            return Err(ApiError::ReasoningOptionsWithoutReasoning);
+        }
+        let instructions = render_template(&self.instructions, &self.template_vars, self.strict)?;
+        if let Some(limit) = self.max_instructions_bytes {
+            let primary = if self.omit_system_message {
+                0
+            } else {
+                instructions.len()
+            };
+            let bytes = primary
+                + self
+                    .additional_instructions
+                    .iter()
+                    .map(String::len)
+                    .sum::<usize>();
+            if bytes > limit {
+                return Err(ApiError::InstructionsTooLarge { bytes, limit });
+            }
+        }
+        if let Some(limit) = self.max_images {
+            let count = image_urls(&self.input, self.tool_output_autodetect_images).count();
+            if count > limit {
+                return Err(ApiError::TooManyImages { count, limit });
+            }
+        }
+        if let Some(limit) = self.max_image_bytes {
+            for bytes in image_urls(&self.input, self.tool_output_autodetect_images)
+                .filter_map(data_uri_decoded_len)
+            {
+                if bytes > limit {
+                    return Err(ApiError::ImageTooLarge { bytes, limit });
                 }
-                ResponseItem::CustomToolCallOutput { call_id, output } => {
-                    messages.push(json!({
-                        "role": "tool",
-                        "tool_call_id": call_id,
-                        "content": output,
-                    }));
+            }
+        }
+        if let Some(routing) = &self.provider_routing
+            && !routing.is_object()
+        {
+            return Err(ApiError::InvalidRequest {
+                message: format!("provider routing must be a JSON object, got {routing}"),
+            });
+        }
+        if let Some(n) = self.n
+            && n > 1
+            && self.stream
+        {
+            return Err(ApiError::InvalidRequest {
+                message: format!("n={n} is not supported with streaming enabled"),
+            });
+        }
+        if let Some(tag) = &self.user_tag
+            && (tag.is_empty() || !tag.is_ascii())
+        {
+            return Err(ApiError::InvalidRequest {
+                message: format!("user tag must be non-empty ASCII, got {tag:?}"),
+            });
+        }
+        if let Some(identifier) = &self.safety_identifier
+            && (identifier.is_empty() || !identifier.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            return Err(ApiError::InvalidRequest {
+                message: format!("safety identifier must be non-empty hex, got {identifier:?}"),
+            });
+        }
+        if self.prompt_cache_key.as_deref() == Some("") {
+            return Err(ApiError::InvalidRequest {
+                message: "prompt cache key must not be empty".to_string(),
+            });
+        }
+        if self.stop_sequences.len() > MAX_STOP_SEQUENCES {
+            return Err(ApiError::InvalidRequest {
+                message: format!(
+                    "at most {MAX_STOP_SEQUENCES} stop sequences are supported, got {}",
+                    self.stop_sequences.len()
+                ),
+            });
+        }
+        for (name, penalty) in [
+            ("frequency_penalty", self.frequency_penalty),
+            ("presence_penalty", self.presence_penalty),
+        ] {
+            if let Some(value) = penalty
+                && !(-2.0..=2.0).contains(&value)
+            {
+                return Err(ApiError::InvalidRequest {
+                    message: format!("{name} must be within -2.0..=2.0, got {value}"),
+                });
+            }
+        }
+        if self.audio_output.is_some() && !self.modalities.contains(&Modality::Audio) {
+            return Err(ApiError::InvalidRequest {
+                message: "audio output requires the audio modality".to_string(),
+            });
+        }
+        if let Some(top) = self.top_logprobs
+            && top > MAX_TOP_LOGPROBS
+        {
+            return Err(ApiError::InvalidRequest {
+                message: format!("top_logprobs must be at most {MAX_TOP_LOGPROBS}, got {top}"),
+            });
+        }
+
+        let mut messages = Vec::<Value>::new();
+        let instructions_content = if self.cache_instructions {
+            json!([{
+                "type": "text",
+                "text": instructions,
+                "cache_control": {"type": "ephemeral"},
+            }])
+        } else {
+            json!(instructions)
+        };
+        let instructions_role = self.instructions_role.as_str();
+        let mut instruction_messages = Vec::new();
+        if !self.omit_system_message {
+            instruction_messages.push(json!({
+                "role": instructions_role,
+                "content": instructions_content,
+            }));
+        }
+        instruction_messages.extend(
+            self.additional_instructions
+                .iter()
+                .filter(|fragment| !fragment.is_empty())
+                .map(|fragment| json!({"role": instructions_role, "content": fragment})),
+        );
+        let mut trailing_instructions = Vec::new();
+        match self.instructions_placement {
+            InstructionsPlacement::First => messages.extend(instruction_messages),
+            InstructionsPlacement::Last => trailing_instructions = instruction_messages,
+        }
+
+        let input: &[ResponseItem] = &self.input;
+        let mut reasoning_by_anchor_index: HashMap<usize, String> = HashMap::new();
+        let mut last_emitted_role: Option<&str> = None;
+        for item in input {
+            match item {
+                ResponseItem::Message { role, .. } => last_emitted_role = Some(role.as_str()),
+                ResponseItem::FunctionCall { .. } | ResponseItem::LocalShellCall { .. } => {
+                    last_emitted_role = Some("assistant")
                 }
-                ResponseItem::GhostSnapshot { .. } => {
-                    continue;
+                ResponseItem::FunctionCallOutput { .. } => {
+                    last_emitted_role = Some(if self.tools_unsupported {
+                        "user"
+                    } else {
+                        "tool"
+                    })
                 }
-                ResponseItem::Reasoning { .. }
-                | ResponseItem::WebSearchCall { .. }
-                | ResponseItem::Other
-                | ResponseItem::Compaction { .. } => {
-                    continue;
+                ResponseItem::Reasoning { .. } | ResponseItem::Other => {}
+                ResponseItem::CustomToolCall { .. } => {
+                    if self.tools_unsupported {
+                        last_emitted_role = Some("assistant");
+                    }
+                }
+                ResponseItem::CustomToolCallOutput { .. } => {
+                    if self.tools_unsupported {
+                        last_emitted_role = Some("user");
+                    }
                 }
+                ResponseItem::WebSearchCall { .. } => {
+                    if self.emit_web_search {
+                        last_emitted_role = Some("assistant");
+                    }
+                }
+                ResponseItem::GhostSnapshot { .. } => {
+                    if self.include_ghost_snapshots {
+                        last_emitted_role = Some("system");
+                    }
+                }
+                ResponseItem::Compaction { .. } => {}
+            }
+        }
+
+        // Downgraded tool outputs are emitted as user messages, so they also end the turn that
+        // trailing reasoning is measured against.
+        let mut last_user_index: Option<usize> = None;
+        for (idx, item) in input.iter().enumerate() {
+            let emitted_as_user = match item {
+                ResponseItem::Message { role, .. } => role == "user",
+                ResponseItem::FunctionCallOutput { .. }
+                | ResponseItem::CustomToolCallOutput { .. } => self.tools_unsupported,
+                _ => false,
+            };
+            if emitted_as_user {
+                last_user_index = Some(idx);
             }
         }
 
-        let payload = json!({
-            "model": self.model,
-            "messages": messages,
-            "stream": true,
-            "tools": self.tools,
-        });
+        let segment_separator = self.reasoning_join.unwrap_or("");
+        let item_separator = self.reasoning_join.unwrap_or("\n");
+        let ends_on_user = matches!(last_emitted_role, Some("user"));
+        if !self.drop_reasoning {
+            for (idx, item) in input.iter().enumerate() {
+                // Reasoning trailing the final user turn has no assistant turn to anchor to;
+                // reasoning attached to earlier assistant turns is still replayed.
+                if ends_on_user
+                    && let Some(u_idx) = last_user_index
+                    && idx > u_idx
+                {
+                    continue;
+                }
+
+                if let ResponseItem::Reasoning {
+                    content: Some(items),
+                    ..
+                } = item
+                {
+                    let segments: Vec<&str> = items
+                        .iter()
+                        .map(|entry| match entry {
+                            ReasoningItemContent::ReasoningText { text: segment }
+                            | ReasoningItemContent::Text { text: segment } => segment.as_str(),
+                        })
+                        .collect();
+                    let text = segments.join(segment_separator);
+                    if text.trim().is_empty() {
+                        continue;
+                    }
+
+                    let mut attached = false;
+                    if idx > 0
+                        && let ResponseItem::Message { role, .. } = &input[idx - 1]
+                        && role == "assistant"
+                    {
+                        merge_reasoning_attachment(
+                            &mut reasoning_by_anchor_index,
+                            idx - 1,
+                            &text,
+                            item_separator,
+                        );
+                        attached = true;
+                    }
+
+                    if !attached && idx + 1 < input.len() {
+                        match &input[idx + 1] {
+                            ResponseItem::FunctionCall { .. }
+                            | ResponseItem::LocalShellCall { .. } => {
+                                merge_reasoning_attachment(
+                                    &mut reasoning_by_anchor_index,
+                                    idx + 1,
+                                    &text,
+                                    item_separator,
+                                );
+                            }
+                            ResponseItem::Message { role, .. } if role == "assistant" => {
+                                merge_reasoning_attachment(
+                                    &mut reasoning_by_anchor_index,
+                                    idx + 1,
+                                    &text,
+                                    item_separator,
+                                );
+                            }
+                            // User messages never carry reasoning, so it would be silently
+                            // dropped; strict mode surfaces the malformed history instead.
+                            // Reasoning after tool activity belongs to an interrupted turn and
+                            // is dropped without an error.
+                            ResponseItem::Message { role, .. }
+                                if role == "user"
+                                    && self.strict
+                                    && !follows_tool_activity(input, idx) =>
+                            {
+                                return Err(ApiError::ReasoningOnUserMessage { index: idx + 1 });
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut recent_assistant_texts: VecDeque<String> = VecDeque::new();
+        let mut emitted_call_ids: HashSet<&str> = HashSet::new();
+        let mut dropped_call_ids: HashSet<&str> = HashSet::new();
+        let mut emitted_output_ids: HashSet<&str> = HashSet::new();
+        // With tools unsupported, outputs are labelled with their call's name and kept in
+        // their own user message, tracked here so real user turns are never merged into it.
+        let mut tool_call_names: HashMap<&str, String> = HashMap::new();
+        let mut tool_output_message: Option<usize> = None;
+
+        for (idx, item) in input.iter().enumerate() {
+            match item {
+                ResponseItem::Message {
+                    role,
+                    content,
+                    name,
+                    ..
+                } => {
+                    let mut text = String::new();
+                    let mut items: Vec<Value> = Vec::new();
+                    let mut saw_media = false;
+
+                    for c in content {
+                        match c {
+                            ContentItem::InputText { text: t }
+                            | ContentItem::OutputText { text: t } => {
+                                text.push_str(t);
+                                items.push(json!({"type":"text","text": t}));
+                            }
+                            ContentItem::InputImage { image_url } => {
+                                saw_media = true;
+                                items.push(
+                                    json!({"type":"image_url","image_url": {"url": image_url}}),
+                                );
+                            }
+                            ContentItem::InputAudio { data, format } => {
+                                saw_media = true;
+                                items.push(json!({
+                                    "type": "input_audio",
+                                    "input_audio": {"data": data, "format": format},
+                                }));
+                            }
+                        }
+                    }
+
+                    // Some providers reject an assistant message with empty content and no tool
+                    // calls; reasoning-only turns are kept because reasoning is a sibling field.
+                    if role == "assistant"
+                        && text.is_empty()
+                        && !saw_media
+                        && !reasoning_by_anchor_index.contains_key(&idx)
+                    {
+                        continue;
+                    }
+
+                    if role == "assistant" && self.dedup_assistant_window > 0 {
+                        if recent_assistant_texts.contains(&text) {
+                            continue;
+                        }
+                        if recent_assistant_texts.len() == self.dedup_assistant_window {
+                            recent_assistant_texts.pop_front();
+                        }
+                        recent_assistant_texts.push_back(text.clone());
+                    }
+
+                    // Text-only messages keep the plain-string shortcut; any image or audio
+                    // (including on replayed assistant turns) requires the structured parts array.
+                    let content_value =
+                        if saw_media || (role == "user" && self.force_structured_content) {
+                            json!(items)
+                        } else {
+                            json!(text)
+                        };
+
+                    // Participant names are limited to `[A-Za-z0-9_-]`; anything else becomes `_`.
+                    let name = name
+                        .as_deref()
+                        .map(sanitize_message_name)
+                        .filter(|name| !name.is_empty());
+
+                    // Some providers reject back-to-back user messages, e.g. when context is
+                    // injected right after a user turn, so fold them into one. Named messages
+                    // are never merged so each participant stays attributed, and neither are
+                    // downgraded tool outputs.
+                    if role == "user"
+                        && name.is_none()
+                        && tool_output_message.is_none_or(|index| index + 1 != messages.len())
+                        && let Some(prev) = messages.last_mut()
+                        && prev.get("role").and_then(Value::as_str) == Some("user")
+                        && prev.get("name").is_none()
+                    {
+                        merge_user_content(prev, content_value);
+                        continue;
+                    }
+
+                    let mut msg = json!({"role": role, "content": content_value});
+                    if let Some(obj) = msg.as_object_mut() {
+                        if let Some(name) = name {
+                            obj.insert("name".to_string(), Value::String(name));
+                        }
+                        if role == "assistant"
+                            && let Some(reasoning) = reasoning_by_anchor_index.get(&idx)
+                        {
+                            attach_reasoning(obj, reasoning, self.reasoning_placement());
+                        }
+                    }
+                    messages.push(msg);
+                }
+                ResponseItem::FunctionCall {
+                    name,
+                    arguments,
+                    call_id,
+                    ..
+                } => {
+                    // Strict mode rejects duplicate ids up front; otherwise the first call wins.
+                    if !emitted_call_ids.insert(call_id) {
+                        dropped_call_ids.insert(call_id);
+                        continue;
+                    }
+                    let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
+                    if self.tools_unsupported {
+                        let name = self.rewrite_tool_name(name).into_owned();
+                        let text = tool_call_text(&name, call_id, arguments);
+                        tool_call_names.insert(call_id, name);
+                        push_tool_call_text(
+                            &mut messages,
+                            &text,
+                            reasoning,
+                            self.reasoning_placement(),
+                        );
+                        continue;
+                    }
+                    let tool_call = json!({
+                        "id": call_id,
+                        "type": "function",
+                        "function": {
+                            "name": self.rewrite_tool_name(name),
+                            "arguments": self.function_arguments(arguments),
+                        }
+                    });
+                    push_tool_call_message(
+                        &mut messages,
+                        tool_call,
+                        reasoning,
+                        self.reasoning_placement(),
+                    );
+                }
+                ResponseItem::LocalShellCall {
+                    id,
+                    call_id,
+                    status,
+                    action,
+                } => {
+                    let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
+                    if self.tools_unsupported {
+                        let call_id = call_id.as_deref().or(id.as_deref()).unwrap_or_default();
+                        let text =
+                            tool_call_text("local_shell", call_id, &json!(action).to_string());
+                        tool_call_names.insert(call_id, "local_shell".to_string());
+                        push_tool_call_text(
+                            &mut messages,
+                            &text,
+                            reasoning,
+                            self.reasoning_placement(),
+                        );
+                        continue;
+                    }
+                    let tool_call = json!({
+                        "id": id.clone().unwrap_or_default(),
+                        "type": "local_shell_call",
+                        "status": status,
+                        "action": action,
+                    });
+                    push_tool_call_message(
+                        &mut messages,
+                        tool_call,
+                        reasoning,
+                        self.reasoning_placement(),
+                    );
+                }
+                ResponseItem::FunctionCallOutput { call_id, output } => {
+                    // Once a duplicate call has been dropped, only the first output for its id
+                    // is kept so the surviving call is answered exactly once.
+                    if !emitted_output_ids.insert(call_id)
+                        && dropped_call_ids.contains(call_id.as_str())
+                    {
+                        continue;
+                    }
+                    let content_value = if let Some(items) = &output.content_items {
+                        if items.is_empty() && self.force_structured_content {
+                            self.plain_tool_content("")
+                        } else {
+                            tool_output_items_content(items, self.tool_output_format)
+                        }
+                    } else if self.tool_output_autodetect_images
+                        && looks_like_image_url(&output.content)
+                    {
+                        json!([{"type": "image_url", "image_url": {"url": output.content.trim()}}])
+                    } else {
+                        self.plain_tool_content(&output.content)
+                    };
+
+                    if self.tools_unsupported {
+                        push_tool_output_text(
+                            &mut messages,
+                            &mut tool_output_message,
+                            &tool_output_label(&tool_call_names, call_id),
+                            content_value,
+                        );
+                        continue;
+                    }
+
+                    let mut tool_msg = json!({
+                        "role": "tool",
+                        "tool_call_id": call_id,
+                        "content": content_value,
+                    });
+                    if self.mark_tool_errors
+                        && output.success == Some(false)
+                        && let Some(obj) = tool_msg.as_object_mut()
+                    {
+                        obj.insert("is_error".to_string(), Value::Bool(true));
+                    }
+                    // Replaying the same output twice in a row makes some models re-run the
+                    // tool; retries with different content are kept.
+                    if messages.last() == Some(&tool_msg) {
+                        continue;
+                    }
+                    messages.push(tool_msg);
+                }
+                ResponseItem::CustomToolCall {
+                    id,
+                    call_id,
+                    name,
+                    input,
+                    status: _,
+                } => {
+                    if self.tools_unsupported {
+                        let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
+                        let text = tool_call_text(name, call_id, input);
+                        tool_call_names.insert(call_id, name.clone());
+                        push_tool_call_text(
+                            &mut messages,
+                            &text,
+                            reasoning,
+                            self.reasoning_placement(),
+                        );
+                        continue;
+                    }
+                    let tool_call = json!({
+                        "id": id,
+                        "type": "custom",
+                        "custom": {
+                            "name": name,
+                            "input": input,
+                        }
+                    });
+                    let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
+                    push_tool_call_message(
+                        &mut messages,
+                        tool_call,
+                        reasoning,
+                        self.reasoning_placement(),
+                    );
+                }
+                ResponseItem::CustomToolCallOutput { call_id, output } => {
+                    if self.tools_unsupported {
+                        push_tool_output_text(
+                            &mut messages,
+                            &mut tool_output_message,
+                            &tool_output_label(&tool_call_names, call_id),
+                            self.plain_tool_content(output),
+                        );
+                        continue;
+                    }
+                    messages.push(json!({
+                        "role": "tool",
+                        "tool_call_id": call_id,
+                        "content": self.plain_tool_content(output),
+                    }));
+                }
+                ResponseItem::WebSearchCall { id, status, action } => {
+                    if !self.emit_web_search {
+                        continue;
+                    }
+                    let reasoning = reasoning_by_anchor_index.get(&idx).map(String::as_str);
+                    if self.tools_unsupported {
+                        let call_id = id.as_deref().unwrap_or_default();
+                        let text =
+                            tool_call_text("web_search", call_id, &json!(action).to_string());
+                        push_tool_call_text(
+                            &mut messages,
+                            &text,
+                            reasoning,
+                            self.reasoning_placement(),
+                        );
+                        continue;
+                    }
+                    let tool_call = json!({
+                        "id": id.clone().unwrap_or_default(),
+                        "type": "web_search",
+                        "status": status,
+                        "action": action,
+                    });
+                    push_tool_call_message(
+                        &mut messages,
+                        tool_call,
+                        reasoning,
+                        self.reasoning_placement(),
+                    );
+                }
+                // `encrypted_content` is an opaque blob only the Responses API can read, so it
+                // has nothing to offer a Chat Completions model.
+                ResponseItem::Compaction { .. } => {
+                    continue;
+                }
+                ResponseItem::GhostSnapshot { ghost_commit } => {
+                    if self.include_ghost_snapshots {
+                        let note = match ghost_commit.parent() {
+                            Some(parent) => format!(
+                                "Workspace snapshot {} (parent {parent}) was taken at this point.",
+                                ghost_commit.id()
+                            ),
+                            None => format!(
+                                "Workspace snapshot {} was taken at this point.",
+                                ghost_commit.id()
+                            ),
+                        };
+                        messages.push(json!({"role": "system", "content": note}));
+                    }
+                }
+                ResponseItem::Reasoning { .. } | ResponseItem::Other => {
+                    continue;
+                }
+            }
+        }
+
+        messages.extend(trailing_instructions);
+
+        // Trim first so the cache breakpoint and prefill below land on the surviving history.
+        if let Some(budget) = self.max_history_bytes {
+            trim_history_to_bytes(&mut messages, budget, instructions_role);
+        }
+
+        if self.cache_up_to_last_user
+            && let Some(last_user) = messages
+                .iter()
+                .rposition(|message| message["role"] == "user")
+            && let Some(stable) = last_user.checked_sub(1)
+        {
+            add_cache_breakpoint(&mut messages[stable]);
+        }
+
+        if let Some(prefill) = &self.assistant_prefill {
+            push_assistant_prefill(&mut messages, prefill);
+        }
+
+        if let Some(transform) = &self.message_transform {
+            for message in &mut messages {
+                transform(message);
+            }
+        }
+
+        if let Some(limit) = self.max_messages
+            && messages.len() > limit
+        {
+            return Err(ApiError::HistoryTooLong {
+                count: messages.len(),
+                limit,
+            });
+        }
+
+        let tools: Cow<'_, [Value]> = if self.tool_name_rewrite.is_some() || self.strict_tools {
+            Cow::Owned(
+                self.tools
+                    .iter()
+                    .map(|tool| {
+                        let mut tool = tool.clone();
+                        if let Some(rewrite) = &self.tool_name_rewrite {
+                            rename_function_tool(&mut tool, rewrite.as_ref());
+                        }
+                        if self.strict_tools {
+                            mark_function_tool_strict(&mut tool);
+                        }
+                        tool
+                    })
+                    .collect(),
+            )
+        } else {
+            Cow::Borrowed(self.tools.as_ref())
+        };
+        // Keys are inserted in a fixed order so serialized payloads are stable: `model`,
+        // `messages`, `stream`, `tools`, the optional controls below, then `extra_body` keys
+        // sorted by name.
+        let mut obj = Map::new();
+        obj.insert("model".to_string(), json!(self.model));
+        obj.insert("messages".to_string(), Value::Array(messages));
+        obj.insert("stream".to_string(), Value::Bool(self.stream));
+        let omit_tools = (self.omit_empty_tools && tools.is_empty()) || self.tools_unsupported;
+        if !omit_tools {
+            obj.insert("tools".to_string(), json!(tools));
+        }
+        if self.stream && (self.include_usage || !self.stream_options.is_empty()) {
+            let mut stream_options = Map::new();
+            if self.include_usage {
+                stream_options.insert("include_usage".to_string(), Value::Bool(true));
+            }
+            for (key, value) in &self.stream_options {
+                stream_options.insert(key.clone(), value.clone());
+            }
+            obj.insert("stream_options".to_string(), Value::Object(stream_options));
+        }
+        // Sampling controls are omitted when unset because some providers reject explicit nulls.
+        if let Some(max_tokens) = self.max_completion_tokens {
+            obj.insert("max_completion_tokens".to_string(), json!(max_tokens));
+        }
+        if let Some(temperature) = self.temperature {
+            obj.insert("temperature".to_string(), json!(temperature));
+        }
+        if let Some(top_p) = self.top_p {
+            obj.insert("top_p".to_string(), json!(top_p));
+        }
+        if let Some(penalty) = self.frequency_penalty {
+            obj.insert("frequency_penalty".to_string(), json!(penalty));
+        }
+        if let Some(penalty) = self.presence_penalty {
+            obj.insert("presence_penalty".to_string(), json!(penalty));
+        }
+        if let Some(n) = self.n
+            && n > 1
+        {
+            obj.insert("n".to_string(), json!(n));
+        }
+        if let Some(seed) = self.seed {
+            obj.insert("seed".to_string(), json!(seed));
+        }
+        if self.logprobs || self.top_logprobs.is_some() {
+            obj.insert("logprobs".to_string(), Value::Bool(true));
+        }
+        if let Some(top) = self.top_logprobs {
+            obj.insert("top_logprobs".to_string(), json!(top));
+        }
+        if !self.logit_bias.is_empty() {
+            // The API requires token ids as string keys.
+            let bias: BTreeMap<String, f64> = self
+                .logit_bias
+                .iter()
+                .map(|(token, bias)| (token.to_string(), bias.clamp(-100.0, 100.0)))
+                .collect();
+            obj.insert("logit_bias".to_string(), json!(bias));
+        }
+        if !self.stop_sequences.is_empty() {
+            obj.insert("stop".to_string(), json!(self.stop_sequences));
+        }
+        if let Some(parallel) = self.parallel_tool_calls
+            && !self.tools.is_empty()
+            && !omit_tools
+        {
+            obj.insert("parallel_tool_calls".to_string(), Value::Bool(parallel));
+        }
+        if let Some(tool_choice) = &self.tool_choice
+            && !omit_tools
+        {
+            let mut tool_choice = tool_choice.clone();
+            if let ToolChoice::Function(name) = &mut tool_choice {
+                let rewritten = self.rewrite_tool_name(name).into_owned();
+                *name = rewritten;
+            }
+            obj.insert("tool_choice".to_string(), tool_choice.to_value());
+        }
+        if let Some(tag) = &self.user_tag {
+            obj.insert("user".to_string(), json!(tag));
+        }
+        if let Some(store) = self.store {
+            obj.insert("store".to_string(), Value::Bool(store));
+        }
+        if !self.modalities.is_empty() {
+            let modalities: Vec<&str> = self
+                .modalities
+                .iter()
+                .copied()
+                .map(Modality::as_str)
+                .collect();
+            obj.insert("modalities".to_string(), json!(modalities));
+        }
+        if let Some(audio) = &self.audio_output {
+            obj.insert(
+                "audio".to_string(),
+                json!({"voice": audio.voice, "format": audio.format}),
+            );
+        }
+        if let Some(tier) = self.service_tier {
+            obj.insert("service_tier".to_string(), json!(tier.as_str()));
+        }
+        if let Some(predicted) = &self.prediction {
+            obj.insert(
+                "prediction".to_string(),
+                json!({"type": "content", "content": predicted}),
+            );
+        }
+        if let Some(key) = &self.prompt_cache_key {
+            obj.insert("prompt_cache_key".to_string(), json!(key));
+        }
+        if let Some(identifier) = &self.safety_identifier {
+            obj.insert("safety_identifier".to_string(), json!(identifier));
+        }
+        if !self.metadata.is_empty() {
+            let metadata: BTreeMap<&String, &String> = self.metadata.iter().collect();
+            obj.insert("metadata".to_string(), json!(metadata));
+        }
+        if let Some(routing) = &self.provider_routing {
+            obj.insert("provider".to_string(), routing.clone());
+        }
+        if let Some(format) = &self.response_format {
+            obj.insert("response_format".to_string(), format.to_value());
+        }
+        let reasoning_enabled = self.enable_reasoning && !self.drop_reasoning;
+        if reasoning_enabled {
+            self.attach_reasoning_controls(&mut obj);
+        }
+        if !self.chat_template_kwargs.is_empty() {
+            let mut kwargs = Map::new();
+            if reasoning_enabled {
+                kwargs.insert("thinking".to_string(), Value::Bool(true));
+            }
+            kwargs.extend(self.chat_template_kwargs.clone());
+            obj.insert("chat_template_kwargs".to_string(), Value::Object(kwargs));
+        }
+        let mut extra: Vec<(&String, &Value)> = self.extra_body.iter().collect();
+        extra.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (key, value) in extra {
+            obj.insert(key.clone(), value.clone());
+        }
+
+        Ok(Value::Object(obj))
+    }
+
+    fn rewrite_tool_name<'n>(&self, name: &'n str) -> Cow<'n, str> {
+        match &self.tool_name_rewrite {
+            Some(rewrite) => Cow::Owned(rewrite(name)),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    fn function_arguments(&self, arguments: &str) -> Value {
+        if self.function_arguments_as_object
+            && let Ok(parsed @ Value::Object(_)) = serde_json::from_str::<Value>(arguments)
+        {
+            return parsed;
+        }
+        json!(arguments)
+    }
+
+    fn plain_tool_content(&self, text: &str) -> Value {
+        if self.force_structured_content {
+            json!([{"type": "text", "text": text}])
+        } else {
+            json!(text)
+        }
+    }
+
+    fn reasoning_placement(&self) -> ReasoningPlacement<'a> {
+        if self.reasoning_as_content_block {
+            ReasoningPlacement::ContentBlock
+        } else {
+            ReasoningPlacement::Field {
+                style: self.reasoning_field_style,
+                separator: self.reasoning_join.unwrap_or("\n"),
+            }
+        }
+    }
+
+    fn attach_reasoning_controls(&self, payload: &mut Map<String, Value>) {
+        let mut reasoning = Map::new();
+        reasoning.insert("enabled".to_string(), Value::Bool(true));
+        if let Some(effort) = self.reasoning_effort {
+            reasoning.insert("effort".to_string(), json!(effort.as_str()));
+        }
+        if let Some(max_tokens) = self.reasoning_max_tokens {
+            reasoning.insert("max_tokens".to_string(), json!(max_tokens));
+        }
+        payload.insert("reasoning".to_string(), Value::Object(reasoning));
+        if let Some(clear) = self.clear_thinking {
+            payload.insert(
+                "thinking".to_string(),
+                json!({"type": "enabled", "clear_thinking": clear}),
+            );
+        }
+    }
+}
+
+fn emitted_tool_call_ids(body: &Value) -> Vec<String> {
+    body["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message["tool_calls"].as_array())
+        .flatten()
+        .filter_map(|tool_call| tool_call["id"].as_str())
+        .map(str::to_string)
+        .collect()
+}
+
+fn rename_function_tool(tool: &mut Value, rewrite: &dyn Fn(&str) -> String) {
+    if let Some(name) = tool.pointer_mut("/function/name")
+        && let Some(current) = name.as_str()
+    {
+        *name = Value::String(rewrite(current));
+    }
+}
+
+fn mark_function_tool_strict(tool: &mut Value) {
+    if tool.get("type").and_then(Value::as_str) != Some("function") {
+        return;
+    }
+    let Some(function) = tool.get_mut("function").and_then(Value::as_object_mut) else {
+        return;
+    };
+    function.entry("strict").or_insert(Value::Bool(true));
+    if let Some(Value::Object(parameters)) = function.get_mut("parameters") {
+        parameters
+            .entry("additionalProperties")
+            .or_insert(Value::Bool(false));
+    }
+}
+
+/// Rejects malformed tool definitions up front instead of surfacing an opaque provider 400.
+fn validate_tools(tools: &[Value]) -> Result<(), ApiError> {
+    for (idx, tool) in tools.iter().enumerate() {
+        let invalid = |reason: &str| ApiError::InvalidRequest {
+            message: format!("invalid tool at index {idx}: {reason}"),
+        };
+        let Some(obj) = tool.as_object() else {
+            return Err(invalid("expected a JSON object"));
+        };
+        let Some(tool_type) = obj.get("type").and_then(Value::as_str) else {
+            return Err(invalid("missing string `type` field"));
+        };
+        if tool_type == "function"
+            && obj
+                .get("function")
+                .and_then(|function| function.get("name"))
+                .and_then(Value::as_str)
+                .is_none()
+        {
+            return Err(invalid("function tool is missing `function.name`"));
+        }
+    }
+    Ok(())
+}
+
+fn validate_metadata(metadata: &HashMap<String, String>) -> Result<(), ApiError> {
+    for (key, value) in metadata {
+        if key.is_empty() {
+            return Err(ApiError::InvalidRequest {
+                message: "metadata keys must be non-empty".to_string(),
+            });
+        }
+        let len = value.chars().count();
+        if len > MAX_METADATA_VALUE_CHARS {
+            return Err(ApiError::InvalidRequest {
+                message: format!(
+                    "metadata value for {key:?} is {len} characters, max is {MAX_METADATA_VALUE_CHARS}"
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn tool_output_items_content(
+    items: &[FunctionCallOutputContentItem],
+    format: ToolOutputFormat,
+) -> Value {
+    match format {
+        ToolOutputFormat::Structured if items.is_empty() => json!(""),
+        ToolOutputFormat::Structured => {
+            let mapped: Vec<Value> = items
+                .iter()
+                .map(|it| match it {
+                    FunctionCallOutputContentItem::InputText { text } => {
+                        json!({"type":"text","text": text})
+                    }
+                    FunctionCallOutputContentItem::InputImage { image_url } => {
+                        json!({"type":"image_url","image_url": {"url": image_url}})
+                    }
+                })
+                .collect();
+            json!(mapped)
+        }
+        ToolOutputFormat::FlatText => {
+            let flattened: Vec<&str> = items
+                .iter()
+                .map(|it| match it {
+                    FunctionCallOutputContentItem::InputText { text } => text.as_str(),
+                    FunctionCallOutputContentItem::InputImage { .. } => {
+                        FLAT_TOOL_OUTPUT_IMAGE_PLACEHOLDER
+                    }
+                })
+                .collect();
+            json!(flattened.join("\n"))
+        }
+    }
+}
+
+/// Tags the last content part of `message` with an ephemeral `cache_control` marker, turning
+/// string content into a single text part. Messages without content (tool-call turns) are left
+/// untouched.
+fn add_cache_breakpoint(message: &mut Value) {
+    let cache_control = json!({"type": "ephemeral"});
+    match message.get_mut("content") {
+        Some(Value::String(text)) => {
+            let part = json!({"type": "text", "text": text, "cache_control": cache_control});
+            message["content"] = json!([part]);
+        }
+        Some(Value::Array(parts)) => {
+            if let Some(Value::Object(last)) = parts.last_mut() {
+                last.insert("cache_control".to_string(), cache_control);
+            }
+        }
+        Some(Value::Null | Value::Bool(_) | Value::Number(_) | Value::Object(_)) | None => {}
+    }
+}
+
+fn push_assistant_prefill(messages: &mut Vec<Value>, prefill: &str) {
+    if let Some(Value::Object(last)) = messages.last_mut()
+        && last.get("role").and_then(Value::as_str) == Some("assistant")
+    {
+        let appended = match last.get_mut("content") {
+            Some(Value::String(text)) => {
+                text.push_str(prefill);
+                true
+            }
+            Some(Value::Array(parts)) => {
+                parts.push(json!({"type": "text", "text": prefill}));
+                true
+            }
+            // Tool-call turns have null content and cannot be continued as text.
+            _ => false,
+        };
+        if appended {
+            last.insert("partial".to_string(), Value::Bool(true));
+            return;
+        }
+    }
+
+    messages.push(json!({
+        "role": "assistant",
+        "content": prefill,
+        "partial": true,
+    }));
+}
+
+/// Drops the oldest turns until the serialized history fits in `budget` bytes. An assistant
+/// message carrying `tool_calls` and the `tool` replies right after it form one turn, so no
+/// reply is left without its call. System messages, messages in the instructions role and the
+/// final user message are never dropped, so the result may still exceed the budget.
+fn trim_history_to_bytes(messages: &mut Vec<Value>, budget: usize, instructions_role: &str) {
+    let size = |message: &Value| message.to_string().len();
+    let mut total: usize = messages.iter().map(size).sum();
+    let last_user = messages
+        .iter()
+        .rposition(|message| message["role"] == "user");
+
+    let mut keep = vec![true; messages.len()];
+    let mut start = 0;
+    while start < messages.len() && total > budget {
+        let mut end = start + 1;
+        if messages[start].get("tool_calls").is_some() {
+            while end < messages.len() && messages[end]["role"] == "tool" {
+                end += 1;
+            }
+        }
+        let turn = &messages[start..end];
+        let protected = last_user.is_some_and(|idx| (start..end).contains(&idx))
+            || turn
+                .iter()
+                .any(|message| message["role"] == "system" || message["role"] == instructions_role);
+        if !protected {
+            for (offset, message) in turn.iter().enumerate() {
+                keep[start + offset] = false;
+                total -= size(message);
+            }
+        }
+        start = end;
+    }
+
+    let mut keep = keep.into_iter();
+    messages.retain(|_| keep.next().unwrap_or(true));
+}
+
+fn looks_like_image_url(content: &str) -> bool {
+    let content = content.trim();
+    if content.is_empty() || content.contains(char::is_whitespace) {
+        return false;
+    }
+    if content.starts_with("data:image/") {
+        return true;
+    }
+    if !(content.starts_with("https://") || content.starts_with("http://")) {
+        return false;
+    }
+    let path = content.split(['?', '#']).next().unwrap_or_default();
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    matches!(extension.as_str(), "png" | "jpg" | "jpeg" | "gif" | "webp")
+}
+
+/// Appends `text` to the reasoning anchored at `anchor`, separating it from earlier reasoning
+/// with `separator` so consecutive segments do not run together.
+fn merge_reasoning_attachment(
+    reasoning_by_anchor_index: &mut HashMap<usize, String>,
+    anchor: usize,
+    text: &str,
+    separator: &str,
+) {
+    let existing = reasoning_by_anchor_index.entry(anchor).or_default();
+    if !existing.is_empty() && !text.is_empty() {
+        existing.push_str(separator);
+    }
+    existing.push_str(text);
+}
+
+/// Every image URL in the history, from both messages and tool outputs. With
+/// `autodetect_images`, plain-text tool outputs that will be sent as an image count as well.
+fn image_urls(input: &[ResponseItem], autodetect_images: bool) -> impl Iterator<Item = &str> {
+    input.iter().flat_map(|item| {
+        let urls: Vec<&str> = match item {
+            ResponseItem::Message { content, .. } => content
+                .iter()
+                .filter_map(|c| match c {
+                    ContentItem::InputImage { image_url } => Some(image_url.as_str()),
+                    ContentItem::InputText { .. }
+                    | ContentItem::InputAudio { .. }
+                    | ContentItem::OutputText { .. } => None,
+                })
+                .collect(),
+            ResponseItem::FunctionCallOutput { output, .. } => match &output.content_items {
+                Some(items) => items
+                    .iter()
+                    .filter_map(|it| match it {
+                        FunctionCallOutputContentItem::InputImage { image_url } => {
+                            Some(image_url.as_str())
+                        }
+                        FunctionCallOutputContentItem::InputText { .. } => None,
+                    })
+                    .collect(),
+                None if autodetect_images && looks_like_image_url(&output.content) => {
+                    vec![output.content.trim()]
+                }
+                None => Vec::new(),
+            },
+            ResponseItem::Reasoning { .. }
+            | ResponseItem::LocalShellCall { .. }
+            | ResponseItem::FunctionCall { .. }
+            | ResponseItem::CustomToolCall { .. }
+            | ResponseItem::CustomToolCallOutput { .. }
+            | ResponseItem::WebSearchCall { .. }
+            | ResponseItem::GhostSnapshot { .. }
+            | ResponseItem::Compaction { .. }
+            | ResponseItem::Other => Vec::new(),
+        };
+        urls
+    })
+}
+
+/// Accepts `http(s)` URLs and well-formed `data:<mime>;base64,<payload>` URIs whose MIME type
+/// is in [`SUPPORTED_IMAGE_MIME_TYPES`].
+fn validate_image_url(url: &str) -> Result<(), ApiError> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return Ok(());
+    }
+    let Some(rest) = url.strip_prefix("data:") else {
+        return Err(ApiError::InvalidImageUrl {
+            message: "expected an http(s) URL or a data URI".to_string(),
+        });
+    };
+    let mime = match rest.split_once(',') {
+        Some((meta, payload)) if !payload.is_empty() => {
+            meta.strip_suffix(";base64").filter(|mime| !mime.is_empty())
+        }
+        _ => None,
+    };
+    let Some(mime) = mime else {
+        return Err(ApiError::InvalidImageUrl {
+            message: "data URI must look like data:<mime>;base64,<payload>".to_string(),
+        });
+    };
+    if !SUPPORTED_IMAGE_MIME_TYPES.contains(&mime.to_ascii_lowercase().as_str()) {
+        return Err(ApiError::UnsupportedImageMime {
+            mime: mime.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Decoded size of a base64 `data:` URI payload, computed from its length alone. Whitespace
+/// from line-wrapped payloads and `=` padding are not counted. Returns `None` for anything
+/// that is not a base64 data URI.
+fn data_uri_decoded_len(url: &str) -> Option<usize> {
+    let (meta, payload) = url.strip_prefix("data:")?.split_once(',')?;
+    if !meta.ends_with(";base64") {
+        return None;
+    }
+    let encoded = payload
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace() && *byte != b'=')
+        .count();
+    Some(encoded * 3 / 4)
+}
+
+/// Whether the nearest non-reasoning item before `idx` is a tool call or tool output, i.e. the
+/// reasoning at `idx` continues an assistant turn that was cut short.
+fn follows_tool_activity(input: &[ResponseItem], idx: usize) -> bool {
+    input[..idx]
+        .iter()
+        .rev()
+        .find(|item| !matches!(item, ResponseItem::Reasoning { .. }))
+        .is_some_and(|item| {
+            matches!(
+                item,
+                ResponseItem::FunctionCall { .. }
+                    | ResponseItem::FunctionCallOutput { .. }
+                    | ResponseItem::CustomToolCall { .. }
+                    | ResponseItem::CustomToolCallOutput { .. }
+                    | ResponseItem::LocalShellCall { .. }
+                    | ResponseItem::WebSearchCall { .. }
+            )
+        })
+}
+
+/// Ensures function call ids are unique and every tool output refers to a tool call that
+/// appeared earlier in the history.
+fn validate_tool_call_ids(input: &[ResponseItem]) -> Result<(), ApiError> {
+    let mut call_ids: HashSet<&str> = HashSet::new();
+    for item in input {
+        match item {
+            ResponseItem::FunctionCall { call_id, .. } => {
+                if !call_ids.insert(call_id) {
+                    return Err(ApiError::DuplicateToolCallId {
+                        call_id: call_id.clone(),
+                    });
+                }
+            }
+            ResponseItem::CustomToolCall { call_id, .. } => {
+                call_ids.insert(call_id);
+            }
+            ResponseItem::LocalShellCall { id, call_id, .. } => {
+                call_ids.extend(id.as_deref());
+                call_ids.extend(call_id.as_deref());
+            }
+            ResponseItem::FunctionCallOutput { call_id, .. }
+            | ResponseItem::CustomToolCallOutput { call_id, .. } => {
+                if !call_ids.contains(call_id.as_str()) {
+                    return Err(ApiError::DanglingToolOutput {
+                        call_id: call_id.clone(),
+                    });
+                }
+            }
+            ResponseItem::Message { .. }
+            | ResponseItem::Reasoning { .. }
+            | ResponseItem::WebSearchCall { .. }
+            | ResponseItem::GhostSnapshot { .. }
+            | ResponseItem::Compaction { .. }
+            | ResponseItem::Other => {}
+        }
+    }
+    Ok(())
+}
+
+/// Requires every tool call to have a matching output later in the history. The final turn is
+/// exempt: a call with no message after it (only other calls, outputs or reasoning) is still
+/// pending execution and legitimately has no output yet.
+fn validate_tool_calls_resolved(input: &[ResponseItem]) -> Result<(), ApiError> {
+    let mut output_ids: HashSet<&str> = HashSet::new();
+    let mut last_message_idx = None;
+    for (idx, item) in input.iter().enumerate() {
+        match item {
+            ResponseItem::FunctionCallOutput { call_id, .. }
+            | ResponseItem::CustomToolCallOutput { call_id, .. } => {
+                output_ids.insert(call_id);
+            }
+            ResponseItem::Message { .. } => last_message_idx = Some(idx),
+            _ => {}
+        }
+    }
+    let Some(last_message_idx) = last_message_idx else {
+        return Ok(());
+    };
+
+    for item in &input[..last_message_idx] {
+        let call_id = match item {
+            ResponseItem::FunctionCall { call_id, .. }
+            | ResponseItem::CustomToolCall { call_id, .. } => Some(call_id.as_str()),
+            ResponseItem::LocalShellCall { id, call_id, .. } => {
+                call_id.as_deref().or(id.as_deref())
+            }
+            _ => None,
+        };
+        if let Some(call_id) = call_id
+            && !output_ids.contains(call_id)
+        {
+            return Err(ApiError::UnresolvedToolCall {
+                call_id: call_id.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Substitutes `{{key}}` placeholders in `template` with values from `vars`; whitespace around
+/// the key is ignored. Unknown placeholders are kept verbatim unless `strict`. Templating is
+/// off while `vars` is empty, so literal braces in untemplated instructions are never rejected.
+fn render_template<'t>(
+    template: &'t str,
+    vars: &HashMap<String, String>,
+    strict: bool,
+) -> Result<Cow<'t, str>, ApiError> {
+    if vars.is_empty() {
+        return Ok(Cow::Borrowed(template));
+    }
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after_open = &rest[start + 2..];
+        let Some(len) = after_open.find("}}") else {
+            break;
+        };
+        let key = after_open[..len].trim();
+        rendered.push_str(&rest[..start]);
+        match vars.get(key) {
+            Some(value) => rendered.push_str(value),
+            None if strict => {
+                return Err(ApiError::InvalidRequest {
+                    message: format!("unknown template variable {key:?} in instructions"),
+                });
+            }
+            None => rendered.push_str(&rest[start..start + len + 4]),
+        }
+        rest = &after_open[len + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(Cow::Owned(rendered))
+}
+
+fn sanitize_message_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Appends `content` to an already emitted user message. Text-only content is joined with a
+/// newline; if either side carries structured parts the result is a single parts array.
+fn merge_user_content(message: &mut Value, content: Value) {
+    let Some(obj) = message.as_object_mut() else {
+        return;
+    };
+    // Taking the value in place keeps `content` at its original key position.
+    let existing = obj
+        .get_mut("content")
+        .map(Value::take)
+        .unwrap_or(Value::Null);
+    let merged = match (existing, content) {
+        (Value::String(mut existing), Value::String(text)) => {
+            existing.push('\n');
+            existing.push_str(&text);
+            Value::String(existing)
+        }
+        (existing, content) => {
+            let mut parts = content_parts(existing);
+            parts.extend(content_parts(content));
+            Value::Array(parts)
+        }
+    };
+    obj.insert("content".to_string(), merged);
+}
+
+fn content_parts(content: Value) -> Vec<Value> {
+    match content {
+        Value::Array(parts) => parts,
+        Value::String(text) if text.is_empty() => Vec::new(),
+        Value::String(text) => vec![json!({"type": "text", "text": text})],
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::Object(_) => Vec::new(),
+    }
+}
+
+/// Where replayed reasoning is placed on an assistant message.
+#[derive(Clone, Copy)]
+enum ReasoningPlacement<'a> {
+    /// A top-level message field; `separator` joins reasoning merged onto the same message.
+    Field {
+        style: ReasoningFieldStyle,
+        separator: &'a str,
+    },
+    ContentBlock,
+}
+
+fn attach_reasoning(
+    obj: &mut Map<String, Value>,
+    reasoning: &str,
+    placement: ReasoningPlacement<'_>,
+) {
+    match placement {
+        ReasoningPlacement::Field { style, separator } => {
+            attach_reasoning_fields(obj, reasoning, style, separator)
+        }
+        ReasoningPlacement::ContentBlock => attach_reasoning_block(obj, reasoning),
+    }
+}
+
+/// Inserts a reasoning part after any earlier reasoning parts, converting string or null
+/// content into a parts array.
+fn attach_reasoning_block(obj: &mut Map<String, Value>, reasoning: &str) {
+    let mut parts = match obj.get_mut("content").map(Value::take) {
+        Some(Value::Array(parts)) => parts,
+        Some(Value::String(text)) if !text.is_empty() => {
+            vec![json!({"type": "text", "text": text})]
+        }
+        _ => Vec::new(),
+    };
+    let insert_at = parts
+        .iter()
+        .take_while(|part| part["type"] == "reasoning")
+        .count();
+    parts.insert(insert_at, json!({"type": "reasoning", "text": reasoning}));
+    obj.insert("content".to_string(), Value::Array(parts));
+}
+
+/// Whether `content` belongs to a tool-call-only assistant message: null, or only reasoning
+/// parts when reasoning is replayed as content blocks.
+fn is_tool_call_content(content: &Value) -> bool {
+    match content {
+        Value::Null => true,
+        Value::Array(parts) => parts.iter().all(|part| part["type"] == "reasoning"),
+        Value::Bool(_) | Value::Number(_) | Value::String(_) | Value::Object(_) => false,
+    }
+}
+
+/// Attaches `reasoning` to an assistant message under the field selected by `style`,
+/// appending to any reasoning already present on the message after `separator`.
+fn attach_reasoning_fields(
+    obj: &mut Map<String, Value>,
+    reasoning: &str,
+    style: ReasoningFieldStyle,
+    separator: &str,
+) {
+    let field = style.field_name();
+    if let Some(Value::String(existing)) = obj.get_mut(field) {
+        if !existing.is_empty() {
+            existing.push_str(separator);
+        }
+        existing.push_str(reasoning);
+    } else {
+        obj.insert(field.to_string(), Value::String(reasoning.to_string()));
+    }
+}
+
+fn push_tool_call_message(
+    messages: &mut Vec<Value>,
+    tool_call: Value,
+    reasoning: Option<&str>,
+    placement: ReasoningPlacement<'_>,
+) {
+    // Chat Completions requires that tool calls are grouped into a single assistant message
+    // (with `tool_calls: [...]`) followed by tool role responses.
+    if let Some(Value::Object(obj)) = messages.last_mut()
+        && obj.get("role").and_then(Value::as_str) == Some("assistant")
+        && obj.get("content").is_some_and(is_tool_call_content)
+        && let Some(tool_calls) = obj.get_mut("tool_calls").and_then(Value::as_array_mut)
+    {
+        tool_calls.push(tool_call);
+        if let Some(reasoning) = reasoning {
+            attach_reasoning(obj, reasoning, placement);
+        }
+        return;
+    }
+
+    let mut msg = json!({
+        "role": "assistant",
+        "content": null,
+        "tool_calls": [tool_call],
+    });
+    if let Some(reasoning) = reasoning
+        && let Some(obj) = msg.as_object_mut()
+    {
+        attach_reasoning(obj, reasoning, placement);
+    }
+    messages.push(msg);
+}
+
+/// Text counterpart of [`push_tool_call_message`] used when tools are unsupported. Calls made
+/// in the same assistant turn are joined onto that turn's text, one per line.
+fn push_tool_call_text(
+    messages: &mut Vec<Value>,
+    text: &str,
+    reasoning: Option<&str>,
+    placement: ReasoningPlacement<'_>,
+) {
+    if let Some(Value::Object(obj)) = messages.last_mut()
+        && obj.get("role").and_then(Value::as_str) == Some("assistant")
+        && !obj.contains_key("tool_calls")
+        && let Some(Value::String(content)) = obj.get_mut("content")
+    {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        content.push_str(text);
+        if let Some(reasoning) = reasoning {
+            attach_reasoning(obj, reasoning, placement);
+        }
+        return;
+    }
+
+    let mut msg = json!({"role": "assistant", "content": text});
+    if let Some(reasoning) = reasoning
+        && let Some(obj) = msg.as_object_mut()
+    {
+        attach_reasoning(obj, reasoning, placement);
+    }
+    messages.push(msg);
+}
+
+/// How a call is written into the assistant's text when tools are unsupported.
+fn tool_call_text(name: &str, call_id: &str, arguments: &str) -> String {
+    format!("Called {name} ({call_id}) with {arguments}")
+}
+
+fn tool_output_label(tool_call_names: &HashMap<&str, String>, call_id: &str) -> String {
+    match tool_call_names.get(call_id) {
+        Some(name) => format!("Output of {name} ({call_id}):"),
+        None => format!("Output of call {call_id}:"),
+    }
+}
+
+/// Text counterpart of a `tool` message used when tools are unsupported. The output is
+/// prefixed with `label` and sent as a user message; consecutive outputs share one message,
+/// whose index is kept in `output_message`, but real user messages are never merged into.
+fn push_tool_output_text(
+    messages: &mut Vec<Value>,
+    output_message: &mut Option<usize>,
+    label: &str,
+    content: Value,
+) {
+    let content = match content {
+        Value::String(text) => Value::String(format!("{label}\n{text}")),
+        Value::Array(mut parts) => {
+            parts.insert(0, json!({"type": "text", "text": label}));
+            Value::Array(parts)
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::Object(_) => {
+            Value::String(label.to_string())
+        }
+    };
+    if output_message.is_some_and(|index| index + 1 == messages.len())
+        && let Some(prev) = messages.last_mut()
+    {
+        merge_user_content(prev, content);
+        return;
+    }
+    messages.push(json!({"role": "user", "content": content}));
+    *output_message = Some(messages.len() - 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::RetryConfig;
+    use crate::provider::WireApi;
+    use assert_matches::assert_matches;
+    use codex_protocol::models::FunctionCallOutputPayload;
+    use codex_protocol::models::LocalShellAction;
+    use codex_protocol::models::LocalShellExecAction;
+    use codex_protocol::models::LocalShellStatus;
+    use codex_protocol::models::WebSearchAction;
+    use codex_protocol::protocol::SessionSource;
+    use codex_protocol::protocol::SubAgentSource;
+    use http::HeaderValue;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn user_message(text: &str) -> ResponseItem {
+        message("user", text)
+    }
+
+    fn message(role: &str, text: &str) -> ResponseItem {
+        ResponseItem::Message {
+            id: None,
+            role: role.to_string(),
+            content: vec![ContentItem::InputText {
+                text: text.to_string(),
+            }],
+            name: None,
+            end_turn: None,
+        }
+    }
+
+    fn provider() -> Provider {
+        Provider {
+            name: "openai".to_string(),
+            base_url: "https://api.openai.com/v1".to_string(),
+            query_params: None,
+            wire: WireApi::Chat,
+            headers: HeaderMap::new(),
+            retry: RetryConfig {
+                max_attempts: 1,
+                base_delay: Duration::from_millis(10),
+                retry_429: false,
+                retry_5xx: true,
+                retry_transport: true,
+            },
+            stream_idle_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn attaches_conversation_and_subagent_headers() {
+        let prompt_input = vec![ResponseItem::Message {
+            id: None,
+            role: "user".to_string(),
+            content: vec![ContentItem::InputText {
+                text: "hi".to_string(),
+            }],
+            name: None,
+            end_turn: None,
+        }];
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .conversation_id(Some("conv-1".into()))
+            .session_source(Some(SessionSource::SubAgent(SubAgentSource::Review)))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.headers.get("session_id"),
+            Some(&HeaderValue::from_static("conv-1"))
+        );
+        assert_eq!(
+            req.headers.get("x-openai-subagent"),
+            Some(&HeaderValue::from_static("review"))
+        );
+    }
+
+    #[test]
+    fn groups_consecutive_tool_calls_into_a_single_assistant_message() {
+        let prompt_input = vec![
+            ResponseItem::Message {
+                id: None,
+                role: "user".to_string(),
+                content: vec![ContentItem::InputText {
+                    text: "read these".to_string(),
+                }],
+                name: None,
+                end_turn: None,
+            },
+            ResponseItem::FunctionCall {
+                id: None,
+                name: "read_file".to_string(),
+                arguments: r#"{"path":"a.txt"}"#.to_string(),
+                call_id: "call-a".to_string(),
+            },
+            ResponseItem::FunctionCall {
+                id: None,
+                name: "read_file".to_string(),
+                arguments: r#"{"path":"b.txt"}"#.to_string(),
+                call_id: "call-b".to_string(),
+            },
+            ResponseItem::FunctionCall {
+                id: None,
+                name: "read_file".to_string(),
+                arguments: r#"{"path":"c.txt"}"#.to_string(),
+                call_id: "call-c".to_string(),
+            },
+            ResponseItem::FunctionCallOutput {
+                call_id: "call-a".to_string(),
+                output: FunctionCallOutputPayload {
+                    content: "A".to_string(),
+                    ..Default::default()
+                },
+            },
+            ResponseItem::FunctionCallOutput {
+                call_id: "call-b".to_string(),
+                output: FunctionCallOutputPayload {
+                    content: "B".to_string(),
+                    ..Default::default()
+                },
+            },
+            ResponseItem::FunctionCallOutput {
+                call_id: "call-c".to_string(),
+                output: FunctionCallOutputPayload {
+                    content: "C".to_string(),
+                    ..Default::default()
+                },
+            },
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let messages = req
+            .body
+            .get("messages")
+            .and_then(|v| v.as_array())
+            .expect("messages array");
+        // system + user + assistant(tool_calls=[...]) + 3 tool outputs
+        assert_eq!(messages.len(), 6);
+
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[1]["role"], "user");
+
+        let tool_calls_msg = &messages[2];
+        assert_eq!(tool_calls_msg["role"], "assistant");
+        assert_eq!(tool_calls_msg["content"], serde_json::Value::Null);
+        let tool_calls = tool_calls_msg["tool_calls"]
+            .as_array()
+            .expect("tool_calls array");
+        assert_eq!(tool_calls.len(), 3);
+        assert_eq!(tool_calls[0]["id"], "call-a");
+        assert_eq!(tool_calls[1]["id"], "call-b");
+        assert_eq!(tool_calls[2]["id"], "call-c");
+
+        assert_eq!(messages[3]["role"], "tool");
+        assert_eq!(messages[3]["tool_call_id"], "call-a");
+        assert_eq!(messages[4]["role"], "tool");
+        assert_eq!(messages[4]["tool_call_id"], "call-b");
+        assert_eq!(messages[5]["role"], "tool");
+        assert_eq!(messages[5]["tool_call_id"], "call-c");
+    }
+
+    #[test]
+    fn emits_sampling_controls_only_when_set() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_completion_tokens(Some(256))
+            .temperature(Some(0.7))
+            .top_p(Some(0.25))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["max_completion_tokens"], json!(256));
+        assert_eq!(req.body["temperature"], json!(0.7));
+        assert_eq!(req.body["top_p"], json!(0.25));
+        assert_eq!(req.body["temperature"].to_string(), "0.7");
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        let body = req.body.as_object().expect("body object");
+        assert!(!body.contains_key("max_completion_tokens"));
+        assert!(!body.contains_key("temperature"));
+        assert!(!body.contains_key("top_p"));
+    }
+
+    #[test]
+    fn emits_stop_sequences() {
+        let prompt_input = vec![user_message("hi")];
+        let stop = vec!["END".to_string(), "###".to_string(), "</json>".to_string()];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .stop_sequences(stop.clone())
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["stop"], json!(stop));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("stop"), None);
+    }
+
+    #[test]
+    fn rejects_more_than_four_stop_sequences() {
+        let prompt_input = vec![user_message("hi")];
+        let stop = (0..5).map(|i| format!("stop-{i}")).collect();
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .stop_sequences(stop)
+            .build(&provider())
+            .err()
+            .expect("too many stop sequences");
+        assert_matches!(err, ApiError::InvalidRequest { .. });
+    }
+
+    #[test]
+    fn serializes_each_tool_choice_variant() {
+        let prompt_input = vec![user_message("hi")];
+        let cases = [
+            (ToolChoice::Auto, json!("auto")),
+            (ToolChoice::None, json!("none")),
+            (ToolChoice::Required, json!("required")),
+            (
+                ToolChoice::Function("read_file".to_string()),
+                json!({"type": "function", "function": {"name": "read_file"}}),
+            ),
+        ];
+
+        for (choice, expected) in cases {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .tool_choice(choice)
+                .build(&provider())
+                .expect("request");
+            assert_eq!(req.body["tool_choice"], expected);
+        }
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("tool_choice"), None);
+    }
+
+    #[test]
+    fn stream_flag_reflects_configuration() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["stream"], json!(true));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .stream(false)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["stream"], json!(false));
+    }
+
+    fn function_call_output(call_id: &str, content: &str) -> ResponseItem {
+        ResponseItem::FunctionCallOutput {
+            call_id: call_id.to_string(),
+            output: FunctionCallOutputPayload {
+                content: content.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn function_call(call_id: &str, name: &str, arguments: &str) -> ResponseItem {
+        ResponseItem::FunctionCall {
+            id: None,
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+            call_id: call_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn skips_consecutive_identical_tool_outputs() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-a", "done"),
+            function_call_output("call-a", "done"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages array");
+        // system + user + assistant(tool_calls) + a single tool output
+        assert_eq!(messages.len(), 4);
+        assert_eq!(
+            messages[3],
+            json!({"role": "tool", "tool_call_id": "call-a", "content": "done"})
+        );
+    }
+
+    #[test]
+    fn keeps_tool_outputs_with_same_call_id_but_different_content() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-a", "failed"),
+            function_call_output("call-a", "done"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages array");
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages[3]["content"], "failed");
+        assert_eq!(messages[4]["content"], "done");
+    }
+
+    #[test]
+    fn reasoning_controls_include_configured_effort() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .enable_reasoning(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["reasoning"], json!({"enabled": true}));
+
+        let cases = [
+            (ReasoningEffort::Low, "low"),
+            (ReasoningEffort::Medium, "medium"),
+            (ReasoningEffort::High, "high"),
+        ];
+        for (effort, expected) in cases {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .enable_reasoning(true)
+                .reasoning_effort(effort)
+                .build(&provider())
+                .expect("request");
+            assert_eq!(
+                req.body["reasoning"],
+                json!({"enabled": true, "effort": expected})
+            );
+        }
+    }
+
+    #[test]
+    fn reasoning_effort_is_ignored_when_reasoning_is_disabled() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .reasoning_effort(ReasoningEffort::High)
+            .reasoning_max_tokens(Some(1024))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("reasoning"), None);
+    }
+
+    #[test]
+    fn strict_mode_rejects_reasoning_options_without_reasoning() {
+        let prompt_input = vec![user_message("hi")];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .reasoning_effort(ReasoningEffort::High)
+            .reasoning_max_tokens(Some(1024))
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("reasoning options without reasoning");
+        assert_matches!(err, ApiError::ReasoningOptionsWithoutReasoning);
+
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .enable_reasoning(true)
+            .reasoning_effort(ReasoningEffort::High)
+            .strict(true)
+            .build(&provider())
+            .expect("reasoning enabled");
+    }
+
+    #[test]
+    fn instructions_message_uses_configured_role() {
+        let prompt_input = vec![user_message("hi")];
+        let cases = [
+            (InstructionsRole::System, "system"),
+            (InstructionsRole::Developer, "developer"),
+        ];
+
+        for (role, expected) in cases {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .instructions_role(role)
+                .build(&provider())
+                .expect("request");
+            assert_eq!(
+                req.body["messages"][0],
+                json!({"role": expected, "content": "inst"})
+            );
+        }
+    }
+
+    fn reasoning(text: &str) -> ResponseItem {
+        ResponseItem::Reasoning {
+            id: String::new(),
+            summary: Vec::new(),
+            content: Some(vec![ReasoningItemContent::ReasoningText {
+                text: text.to_string(),
+            }]),
+            encrypted_content: None,
+        }
+    }
+
+    #[test]
+    fn coalesced_tool_calls_keep_order_and_reasoning() {
+        let prompt_input = vec![
+            user_message("read both"),
+            reasoning("first"),
+            function_call("call-a", "read_file", r#"{"path":"a.txt"}"#),
+            reasoning("second"),
+            function_call("call-b", "read_file", r#"{"path":"b.txt"}"#),
+            function_call_output("call-a", "A"),
+            function_call_output("call-b", "B"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages array");
+        // system + user + assistant(tool_calls=[a, b]) + 2 tool outputs
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            messages[2],
+            json!({
+                "role": "assistant",
+                "content": null,
+                "reasoning": "first\nsecond",
+                "tool_calls": [
+                    {
+                        "id": "call-a",
+                        "type": "function",
+                        "function": {"name": "read_file", "arguments": r#"{"path":"a.txt"}"#},
+                    },
+                    {
+                        "id": "call-b",
+                        "type": "function",
+                        "function": {"name": "read_file", "arguments": r#"{"path":"b.txt"}"#},
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn assistant_message_with_image_uses_structured_content() {
+        let prompt_input = vec![
+            user_message("draw a cat"),
+            ResponseItem::Message {
+                id: None,
+                role: "assistant".to_string(),
+                content: vec![
+                    ContentItem::OutputText {
+                        text: "here it is".to_string(),
+                    },
+                    ContentItem::InputImage {
+                        image_url: "https://example.com/cat.png".to_string(),
+                    },
+                ],
+                name: None,
+                end_turn: None,
+            },
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][2],
+            json!({
+                "role": "assistant",
+                "content": [
+                    {"type": "text", "text": "here it is"},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn drop_reasoning_removes_all_reasoning_fields() {
+        let prompt_input = vec![
+            user_message("read it"),
+            reasoning("thinking"),
+            function_call("call-a", "read_file", "{}"),
+            function_call_output("call-a", "A"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .enable_reasoning(true)
+            .reasoning_effort(ReasoningEffort::High)
+            .drop_reasoning(true)
+            .build(&provider())
+            .expect("request");
+
+        let serialized = req.body.to_string();
+        assert!(
+            !serialized.contains("reasoning"),
+            "unexpected reasoning in {serialized}"
+        );
+    }
+
+    fn invalid_request_message(result: Result<ChatRequest, ApiError>) -> String {
+        match result {
+            Err(ApiError::InvalidRequest { message }) => message,
+            Err(other) => panic!("expected invalid request error, got {other:?}"),
+            Ok(_) => panic!("expected invalid request error, got a request"),
+        }
+    }
+
+    #[test]
+    fn rejects_tool_without_type() {
+        let prompt_input = vec![user_message("hi")];
+        let tools = vec![json!({"function": {"name": "read_file"}})];
+
+        let message = invalid_request_message(
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools).build(&provider()),
+        );
+        assert_eq!(
+            message,
+            "invalid tool at index 0: missing string `type` field"
+        );
+    }
+
+    #[test]
+    fn rejects_non_object_tool() {
+        let prompt_input = vec![user_message("hi")];
+        let tools = vec![
+            json!({"type": "function", "function": {"name": "read_file"}}),
+            json!("read_file"),
+        ];
+
+        let message = invalid_request_message(
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools).build(&provider()),
+        );
+        assert_eq!(message, "invalid tool at index 1: expected a JSON object");
+    }
+
+    #[test]
+    fn accepts_valid_function_tool() {
+        let prompt_input = vec![user_message("hi")];
+        let tools = vec![json!({
+            "type": "function",
+            "function": {"name": "read_file", "parameters": {"type": "object"}},
+        })];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["tools"], json!(tools));
+    }
+
+    fn read_file_tool() -> Value {
+        json!({"type": "function", "function": {"name": "read_file"}})
+    }
+
+    #[test]
+    fn emits_parallel_tool_calls_when_tools_present() {
+        let prompt_input = vec![user_message("hi")];
+        let tools = vec![read_file_tool()];
+
+        for parallel in [true, false] {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+                .parallel_tool_calls(Some(parallel))
+                .build(&provider())
+                .expect("request");
+            assert_eq!(req.body["parallel_tool_calls"], json!(parallel));
+        }
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("parallel_tool_calls"), None);
+    }
+
+    #[test]
+    fn omits_parallel_tool_calls_without_tools() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .parallel_tool_calls(Some(false))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("parallel_tool_calls"), None);
+    }
+
+    #[test]
+    fn emits_json_object_response_format() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .response_format(ResponseFormat::JsonObject)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["response_format"], json!({"type": "json_object"}));
+    }
+
+    #[test]
+    fn emits_strict_json_schema_response_format_verbatim() {
+        let prompt_input = vec![user_message("hi")];
+        let schema = json!({
+            "type": "object",
+            "properties": {"answer": {"type": "string"}},
+            "required": ["answer"],
+            "additionalProperties": false,
+        });
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .response_format(ResponseFormat::JsonSchema {
+                name: "answer".to_string(),
+                schema: schema.clone(),
+                strict: true,
+            })
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["response_format"],
+            json!({
+                "type": "json_schema",
+                "json_schema": {"name": "answer", "schema": schema, "strict": true},
+            })
+        );
+    }
+
+    #[test]
+    fn merges_extra_body_fields_over_standard_ones() {
+        let prompt_input = vec![user_message("hi")];
+        let mut extra = Map::new();
+        extra.insert("top_k".to_string(), json!(40));
+        extra.insert("temperature".to_string(), json!(1.0));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .temperature(Some(0.5))
+            .extra_body(extra)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["top_k"], json!(40));
+        assert_eq!(req.body["temperature"], json!(1.0));
+    }
+
+    #[test]
+    fn merges_extra_headers_with_conversation_headers() {
+        let prompt_input = vec![user_message("hi")];
+        let mut extra = HeaderMap::new();
+        extra.insert("x-provider-region", HeaderValue::from_static("eu-west"));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .conversation_id(Some("conv-1".into()))
+            .extra_headers(extra)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.headers.get("session_id"),
+            Some(&HeaderValue::from_static("conv-1"))
+        );
+        assert_eq!(
+            req.headers.get("x-provider-region"),
+            Some(&HeaderValue::from_static("eu-west"))
+        );
+    }
+
+    #[test]
+    fn extra_headers_override_conversation_headers() {
+        let prompt_input = vec![user_message("hi")];
+        let mut extra = HeaderMap::new();
+        extra.insert("session_id", HeaderValue::from_static("override"));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .conversation_id(Some("conv-1".into()))
+            .extra_headers(extra)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.headers.get("session_id"),
+            Some(&HeaderValue::from_static("override"))
+        );
+    }
+
+    #[test]
+    fn skips_empty_assistant_messages() {
+        let prompt_input = vec![
+            user_message("hi"),
+            message("assistant", ""),
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-a", "done"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let roles: Vec<&str> = req.body["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .filter_map(|msg| msg["role"].as_str())
+            .collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "tool"]);
+        assert_eq!(req.body["messages"][2]["content"], Value::Null);
+    }
+
+    #[test]
+    fn keeps_empty_assistant_message_with_reasoning() {
+        let prompt_input = vec![
+            user_message("hi"),
+            reasoning("thinking"),
+            message("assistant", ""),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][2],
+            json!({"role": "assistant", "content": "", "reasoning": "thinking"})
+        );
+    }
+
+    #[test]
+    fn user_tag_sets_header_and_body_field() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .user_tag(Some("tenant-42".to_string()))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["user"], json!("tenant-42"));
+        assert_eq!(
+            req.headers.get("x-codex-user"),
+            Some(&HeaderValue::from_static("tenant-42"))
+        );
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("user"), None);
+        assert_eq!(req.headers.get("x-codex-user"), None);
+    }
+
+    #[test]
+    fn rejects_empty_or_non_ascii_user_tag() {
+        let prompt_input = vec![user_message("hi")];
+
+        for tag in ["", "tenant-é"] {
+            let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .user_tag(Some(tag.to_string()))
+                .build(&provider())
+                .err()
+                .expect("invalid user tag");
+            assert_matches!(err, ApiError::InvalidRequest { .. });
+        }
+    }
+
+    #[test]
+    fn cache_instructions_marks_system_message() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .cache_instructions(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"][0],
+            json!({
+                "role": "system",
+                "content": [{
+                    "type": "text",
+                    "text": "inst",
+                    "cache_control": {"type": "ephemeral"},
+                }],
+            })
+        );
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"][0],
+            json!({"role": "system", "content": "inst"})
+        );
+    }
+
+    #[test]
+    fn merges_adjacent_user_messages() {
+        let prompt_input = vec![user_message("first"), user_message("second")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "system", "content": "inst"},
+                {"role": "user", "content": "first\nsecond"},
+            ])
+        );
+    }
+
+    #[test]
+    fn merges_adjacent_user_messages_with_images_into_parts() {
+        let prompt_input = vec![
+            user_message("look"),
+            ResponseItem::Message {
+                id: None,
+                role: "user".to_string(),
+                content: vec![ContentItem::InputImage {
+                    image_url: "https://example.com/cat.png".to_string(),
+                }],
+                name: None,
+                end_turn: None,
+            },
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][1],
+            json!({
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "look"},
+                    {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn build_body_only_matches_build() {
+        let prompt_input = vec![
+            user_message("read it"),
+            reasoning("thinking"),
+            function_call("call-a", "read_file", "{}"),
+            function_call_output("call-a", "A"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .conversation_id(Some("conv-1".into()))
+            .temperature(Some(0.5))
+            .build(&provider())
+            .expect("request");
+        let body = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .conversation_id(Some("conv-1".into()))
+            .temperature(Some(0.5))
+            .build_body_only()
+            .expect("body");
+
+        assert_eq!(req.body, body);
+    }
+
+    #[test]
+    fn attaches_reasoning_to_local_shell_call() {
+        let prompt_input = vec![
+            user_message("list files"),
+            reasoning("need ls"),
+            ResponseItem::LocalShellCall {
+                id: Some("shell-1".to_string()),
+                call_id: None,
+                status: LocalShellStatus::Completed,
+                action: LocalShellAction::Exec(LocalShellExecAction {
+                    command: vec!["ls".to_string()],
+                    timeout_ms: None,
+                    working_directory: None,
+                    env: None,
+                    user: None,
+                }),
+            },
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let shell_msg = &req.body["messages"][2];
+        assert_eq!(shell_msg["reasoning"], json!("need ls"));
+        assert_eq!(shell_msg["tool_calls"][0]["id"], json!("shell-1"));
+        assert_eq!(
+            shell_msg["tool_calls"][0]["type"],
+            json!("local_shell_call")
+        );
+    }
+
+    #[test]
+    fn estimates_tokens_for_text_messages() {
+        let prompt_input = vec![user_message("hello world!")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        // system: "system" (2) + "inst" (1) + overhead (4)
+        // user: "user" (1) + "hello world!" (3) + overhead (4)
+        assert_eq!(req.estimate_tokens(), 15);
+    }
+
+    #[test]
+    fn estimates_tokens_with_custom_counter_and_skips_images() {
+        let prompt_input = vec![
+            ResponseItem::Message {
+                id: None,
+                role: "user".to_string(),
+                content: vec![
+                    ContentItem::InputText {
+                        text: "what is this".to_string(),
+                    },
+                    ContentItem::InputImage {
+                        image_url: "data:image/png;base64,AAAA".to_string(),
+                    },
+                ],
+                name: None,
+                end_turn: None,
+            },
+            function_call("call-a", "read_file", r#"{"path":"a.txt"}"#),
+            function_call_output("call-a", "A"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let words = |text: &str| text.split_whitespace().count();
+        // One "token" per word in every string value except image URLs, plus 4 per message.
+        assert_eq!(req.estimate_tokens_with(words), 2 + 6 + 5 + 3 + 4 * 4);
+    }
+
+    #[test]
+    fn emits_seed_as_json_number() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .seed(Some(1234))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["seed"], json!(1234));
+        assert_eq!(req.body["seed"].as_u64(), Some(1234));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("seed"), None);
+    }
+
+    #[test]
+    fn top_logprobs_implies_logprobs() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .top_logprobs(Some(5))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["logprobs"], json!(true));
+        assert_eq!(req.body["top_logprobs"], json!(5));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("logprobs"), None);
+        assert_eq!(req.body.get("top_logprobs"), None);
+    }
+
+    #[test]
+    fn rejects_top_logprobs_above_limit() {
+        let prompt_input = vec![user_message("hi")];
+
+        let message = invalid_request_message(
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .top_logprobs(Some(21))
+                .build(&provider()),
+        );
+        assert_eq!(message, "top_logprobs must be at most 20, got 21");
+    }
+
+    #[test]
+    fn message_count_reflects_coalesced_payload() {
+        let prompt_input = vec![
+            user_message("read both"),
+            function_call("call-a", "read_file", "{}"),
+            function_call("call-b", "read_file", "{}"),
+            function_call_output("call-a", "A"),
+            function_call_output("call-b", "B"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_messages(Some(5))
+            .build(&provider())
+            .expect("request");
+        // system + user + one assistant(tool_calls) + 2 tool outputs
+        assert_eq!(req.message_count(), 5);
+    }
+
+    #[test]
+    fn max_messages_rejects_oversized_history() {
+        let prompt_input = vec![
+            user_message("hi"),
+            message("assistant", "hello"),
+            user_message("again"),
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_messages(Some(3))
+            .build(&provider())
+            .err()
+            .expect("history too long");
+        assert_matches!(err, ApiError::HistoryTooLong { count: 4, limit: 3 });
+    }
+
+    #[test]
+    fn places_instructions_last_when_configured() {
+        let prompt_input = vec![
+            user_message("hi"),
+            reasoning("thinking"),
+            message("assistant", "hello"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .instructions_placement(InstructionsPlacement::Last)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": "hello", "reasoning": "thinking"},
+                {"role": "system", "content": "inst"},
+            ])
+        );
+    }
+
+    #[test]
+    fn emits_penalties_when_set() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .frequency_penalty(Some(0.1))
+            .presence_penalty(Some(-1.5))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["frequency_penalty"], json!(0.1));
+        assert_eq!(req.body["presence_penalty"], json!(-1.5));
+        assert_eq!(req.body["frequency_penalty"].to_string(), "0.1");
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("frequency_penalty"), None);
+        assert_eq!(req.body.get("presence_penalty"), None);
+    }
+
+    #[test]
+    fn rejects_out_of_range_penalty() {
+        let prompt_input = vec![user_message("hi")];
+
+        let message = invalid_request_message(
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .presence_penalty(Some(2.5))
+                .build(&provider()),
+        );
+        assert_eq!(
+            message,
+            "presence_penalty must be within -2.0..=2.0, got 2.5"
+        );
+    }
+
+    #[test]
+    fn emits_additional_instructions_in_order() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .additional_instructions(vec![
+                "policy".to_string(),
+                String::new(),
+                "persona".to_string(),
+            ])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "system", "content": "inst"},
+                {"role": "system", "content": "policy"},
+                {"role": "system", "content": "persona"},
+                {"role": "user", "content": "hi"},
+            ])
+        );
+    }
+
+    #[test]
+    fn strict_mode_rejects_dangling_tool_output() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-b", "done"),
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("dangling tool output");
+        assert_matches!(err, ApiError::DanglingToolOutput { call_id } if call_id == "call-b");
+    }
+
+    #[test]
+    fn lenient_mode_passes_dangling_tool_output_through() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call_output("call-b", "done"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"][2],
+            json!({"role": "tool", "tool_call_id": "call-b", "content": "done"})
+        );
+    }
+
+    #[test]
+    fn separates_reasoning_merged_onto_one_assistant_message() {
+        let prompt_input = vec![
+            user_message("hi"),
+            reasoning("first"),
+            message("assistant", "answer"),
+            reasoning("second"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][2],
+            json!({"role": "assistant", "content": "answer", "reasoning": "first\nsecond"})
+        );
+    }
+
+    #[test]
+    fn emits_metadata_object() {
+        let prompt_input = vec![user_message("hi")];
+        let metadata = HashMap::from([
+            ("team".to_string(), "infra".to_string()),
+            ("run".to_string(), "42".to_string()),
+        ]);
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .metadata(metadata)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["metadata"], json!({"run": "42", "team": "infra"}));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("metadata"), None);
+    }
+
+    #[test]
+    fn rejects_overlong_metadata_value() {
+        let prompt_input = vec![user_message("hi")];
+        let metadata = HashMap::from([("note".to_string(), "x".repeat(513))]);
+
+        let message = invalid_request_message(
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .metadata(metadata)
+                .build(&provider()),
+        );
+        assert_eq!(
+            message,
+            "metadata value for \"note\" is 513 characters, max is 512"
+        );
+    }
+
+    #[test]
+    fn emits_n_for_non_streaming_requests() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .stream(false)
+            .n(Some(3))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["n"], json!(3));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .n(Some(1))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("n"), None);
+    }
+
+    #[test]
+    fn rejects_n_with_streaming() {
+        let prompt_input = vec![user_message("hi")];
+
+        let message = invalid_request_message(
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .n(Some(2))
+                .build(&provider()),
+        );
+        assert_eq!(message, "n=2 is not supported with streaming enabled");
+    }
+
+    #[test]
+    fn keeps_mid_history_reasoning_when_last_message_is_user() {
+        let prompt_input = vec![
+            user_message("hi"),
+            reasoning("greeting"),
+            message("assistant", "hello"),
+            user_message("how are you?"),
+            reasoning("stale"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "system", "content": "inst"},
+                {"role": "user", "content": "hi"},
+                {"role": "assistant", "content": "hello", "reasoning": "greeting"},
+                {"role": "user", "content": "how are you?"},
+            ])
+        );
+    }
+
+    #[test]
+    fn flattens_structured_tool_output_to_text() {
+        let prompt_input = vec![
+            user_message("screenshot"),
+            function_call("call-a", "screenshot", "{}"),
+            ResponseItem::FunctionCallOutput {
+                call_id: "call-a".to_string(),
+                output: FunctionCallOutputPayload {
+                    content: String::new(),
+                    content_items: Some(vec![
+                        FunctionCallOutputContentItem::InputText {
+                            text: "captured".to_string(),
+                        },
+                        FunctionCallOutputContentItem::InputImage {
+                            image_url: "data:image/png;base64,AAAA".to_string(),
+                        },
+                        FunctionCallOutputContentItem::InputText {
+                            text: "done".to_string(),
+                        },
+                    ]),
+                    success: None,
+                },
+            },
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .tool_output_format(ToolOutputFormat::FlatText)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][3],
+            json!({
+                "role": "tool",
+                "tool_call_id": "call-a",
+                "content": "captured\n[image omitted]\ndone",
+            })
+        );
+    }
+
+    #[test]
+    fn pretty_body_matches_serde_pretty_printing() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.pretty_body(),
+            serde_json::to_string_pretty(&req.body).expect("pretty body")
+        );
+    }
+
+    #[test]
+    fn redacted_pretty_body_replaces_image_data_urls() {
+        let data_url = "data:image/png;base64,AAAA";
+        let prompt_input = vec![ResponseItem::Message {
+            id: None,
+            role: "user".to_string(),
+            content: vec![
+                ContentItem::InputText {
+                    text: "look".to_string(),
+                },
+                ContentItem::InputImage {
+                    image_url: data_url.to_string(),
+                },
+                ContentItem::InputImage {
+                    image_url: "https://example.com/cat.png".to_string(),
+                },
+            ],
+            name: None,
+            end_turn: None,
+        }];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let redacted: Value =
+            serde_json::from_str(&req.redacted_pretty_body()).expect("redacted json");
+        assert_eq!(
+            redacted["messages"][1]["content"],
+            json!([
+                {"type": "text", "text": "look"},
+                {"type": "image_url", "image_url": {"url": "<image:3 bytes>"}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+            ])
+        );
+        assert_eq!(
+            req.body["messages"][1]["content"][1]["image_url"]["url"],
+            data_url
+        );
+    }
+
+    #[test]
+    fn emits_provider_routing_verbatim() {
+        let prompt_input = vec![user_message("hi")];
+        let routing = json!({"order": ["anthropic", "openai"], "allow_fallbacks": false});
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .provider_routing(routing.clone())
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["provider"], routing);
+    }
+
+    #[test]
+    fn rejects_non_object_provider_routing() {
+        let prompt_input = vec![user_message("hi")];
+
+        let message = invalid_request_message(
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .provider_routing(json!(["anthropic"]))
+                .build(&provider()),
+        );
+        assert_eq!(
+            message,
+            "provider routing must be a JSON object, got [\"anthropic\"]"
+        );
+    }
+
+    #[test]
+    fn emits_sanitized_message_name() {
+        let named = |name: &str, text: &str| match user_message(text) {
+            ResponseItem::Message {
+                id,
+                role,
+                content,
+                end_turn,
+                ..
+            } => ResponseItem::Message {
+                id,
+                role,
+                content,
+                name: Some(name.to_string()),
+                end_turn,
+            },
+            other => other,
+        };
+        // Locally built user input carries no id; the name travels on the item itself.
+        let prompt_input = vec![
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-a", "done"),
+            named("alice.smith", "hi from alice"),
+            named("bob", "hi from bob"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages array");
+        assert_eq!(
+            messages[3..].to_vec(),
+            vec![
+                json!({"role": "user", "content": "hi from alice", "name": "alice_smith"}),
+                json!({"role": "user", "content": "hi from bob", "name": "bob"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn include_usage_requires_streaming() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .include_usage(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["stream_options"], json!({"include_usage": true}));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .stream(false)
+            .include_usage(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("stream_options"), None);
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("stream_options"), None);
+    }
+
+    #[test]
+    fn reasoning_field_style_selects_field_name() {
+        let prompt_input = vec![
+            user_message("read it"),
+            reasoning("plan"),
+            function_call("call-a", "read_file", "{}"),
+            function_call_output("call-a", "A"),
+            reasoning("summary"),
+            message("assistant", "done"),
+        ];
+        let cases = [
+            (ReasoningFieldStyle::Default, "reasoning"),
+            (ReasoningFieldStyle::ReasoningContent, "reasoning_content"),
+            (ReasoningFieldStyle::ThinkingBlock, "thinking"),
+        ];
+
+        for (style, field) in cases {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .reasoning_field_style(style)
+                .build(&provider())
+                .expect("request");
+            let messages = req.body["messages"].as_array().expect("messages array");
+            assert_eq!(messages[2][field], json!("plan"));
+            assert_eq!(messages[4][field], json!("summary"));
+        }
+    }
+
+    fn owned_builder(question: &str) -> ChatRequestBuilder<'static> {
+        ChatRequestBuilder::new_owned(
+            "gpt-test".to_string(),
+            format!("answer: {question}"),
+            vec![user_message(question)],
+            vec![read_file_tool()],
+            true,
+        )
+    }
+
+    #[test]
+    fn owned_builder_outlives_its_inputs() {
+        let req = owned_builder("why?").build(&provider()).expect("request");
+
+        assert_eq!(req.body["model"], json!("gpt-test"));
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "system", "content": "answer: why?"},
+                {"role": "user", "content": "why?"},
+            ])
+        );
+        assert_eq!(req.body["tools"], json!([read_file_tool()]));
+        assert_eq!(req.body["reasoning"], json!({"enabled": true}));
+    }
+
+    fn user_image_message(image_url: &str) -> ResponseItem {
+        ResponseItem::Message {
+            id: None,
+            role: "user".to_string(),
+            content: vec![ContentItem::InputImage {
+                image_url: image_url.to_string(),
+            }],
+            name: None,
+            end_turn: None,
+        }
+    }
+
+    #[test]
+    fn strict_mode_accepts_https_and_data_image_urls() {
+        for url in [
+            "https://example.com/cat.png",
+            "data:image/png;base64,iVBORw0KGgo=",
+        ] {
+            let prompt_input = vec![user_image_message(url)];
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .strict(true)
+                .build(&provider())
+                .expect("request");
+            assert_eq!(
+                req.body["messages"][1]["content"][0]["image_url"]["url"],
+                json!(url)
+            );
+        }
+    }
+
+    #[test]
+    fn strict_mode_rejects_malformed_data_uri() {
+        let prompt_input = vec![user_image_message("data:image/png,iVBORw0KGgo=")];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("invalid image url");
+        assert_matches!(err, ApiError::InvalidImageUrl { .. });
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("lenient request");
+        assert_eq!(
+            req.body["messages"][1]["content"][0]["image_url"]["url"],
+            json!("data:image/png,iVBORw0KGgo=")
+        );
+    }
+
+    #[test]
+    fn input_audio_forces_structured_content() {
+        let prompt_input = vec![ResponseItem::Message {
+            id: None,
+            role: "user".to_string(),
+            content: vec![
+                ContentItem::InputText {
+                    text: "transcribe this".to_string(),
+                },
+                ContentItem::InputAudio {
+                    data: "UklGRg==".to_string(),
+                    format: "wav".to_string(),
+                },
+            ],
+            name: None,
+            end_turn: None,
+        }];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][1]["content"],
+            json!([
+                {"type": "text", "text": "transcribe this"},
+                {"type": "input_audio", "input_audio": {"data": "UklGRg==", "format": "wav"}},
+            ])
+        );
+    }
+
+    #[test]
+    fn message_transform_rewrites_messages_in_place() {
+        let prompt_input = vec![user_message("hi"), message("assistant", "hello there")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .message_transform(Box::new(|message: &mut Value| {
+                if message["role"] == "assistant"
+                    && let Some(text) = message["content"].as_str()
+                {
+                    message["content"] = json!(text.to_uppercase());
+                }
+            }))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(req.body["messages"][1]["content"], json!("hi"));
+        assert_eq!(req.body["messages"][2]["content"], json!("HELLO THERE"));
+    }
+
+    #[test]
+    fn emits_logit_bias_with_string_keys_and_clamped_values() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .logit_bias(HashMap::from([(50256, -250.0), (1234, 0.3), (42, 101.0)]))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["logit_bias"],
+            json!({"50256": -100.0, "1234": 0.3, "42": 100.0})
+        );
+        assert_eq!(req.body["logit_bias"]["1234"].to_string(), "0.3");
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("logit_bias"), None);
+    }
+
+    #[test]
+    fn max_history_bytes_drops_oldest_messages_first() {
+        let prompt_input = vec![
+            user_message("first question"),
+            message("assistant", &"a".repeat(200)),
+            user_message("second question"),
+            message("assistant", "short answer"),
+            user_message("latest question"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_history_bytes(Some(200))
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages array");
+        let contents: Vec<&str> = messages
+            .iter()
+            .map(|message| message["content"].as_str().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            contents,
+            vec!["inst", "second question", "short answer", "latest question"]
+        );
+    }
+
+    #[test]
+    fn max_history_bytes_keeps_instructions_and_last_user_message() {
+        let prompt_input = vec![
+            message("assistant", "earlier answer"),
+            user_message(&"q".repeat(100)),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_history_bytes(Some(1))
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages array");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], json!("system"));
+        assert_eq!(messages[1]["content"], json!("q".repeat(100)));
+    }
+
+    #[test]
+    fn max_history_bytes_drops_tool_calls_with_their_outputs() {
+        let prompt_input = vec![
+            user_message("first question"),
+            function_call(
+                "call-a",
+                "read_file",
+                &format!(r#"{{"path":"{}"}}"#, "a".repeat(200)),
+            ),
+            function_call_output("call-a", "ok"),
+            ghost_snapshot("abc123"),
+            user_message("latest question"),
+        ];
+        let builder = || {
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .instructions_role(InstructionsRole::Developer)
+                .include_ghost_snapshots(true)
+        };
+        let full = builder().build(&provider()).expect("request");
+        let full_messages = full.body["messages"].as_array().expect("messages array");
+        let total: usize = full_messages.iter().map(|m| m.to_string().len()).sum();
+        // Dropping the first user message and the tool-call message alone would fit; the tool
+        // reply must still go with its call.
+        let budget =
+            total - full_messages[1].to_string().len() - full_messages[2].to_string().len();
+
+        let req = builder()
+            .max_history_bytes(Some(budget))
+            .build(&provider())
+            .expect("request");
+
+        let roles: Vec<&str> = req.body["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .filter_map(|message| message["role"].as_str())
+            .collect();
+        assert_eq!(roles, vec!["developer", "system", "user"]);
+    }
+
+    #[test]
+    fn max_history_bytes_keeps_prefill_and_cache_breakpoint() {
+        let prompt_input = vec![
+            user_message("first question"),
+            message("assistant", &"a".repeat(200)),
+            user_message("latest question"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .cache_up_to_last_user(true)
+            .assistant_prefill(Some("Sure,".to_string()))
+            .max_history_bytes(Some(1))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {
+                    "role": "system",
+                    "content": [{
+                        "type": "text",
+                        "text": "inst",
+                        "cache_control": {"type": "ephemeral"},
+                    }],
+                },
+                {"role": "user", "content": "latest question"},
+                {"role": "assistant", "content": "Sure,", "partial": true},
+            ])
+        );
+    }
+
+    fn web_search_call(query: &str) -> ResponseItem {
+        ResponseItem::WebSearchCall {
+            id: Some("ws_1".to_string()),
+            status: Some("completed".to_string()),
+            action: Some(WebSearchAction::Search {
+                query: Some(query.to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn emits_web_search_calls_when_enabled() {
+        let prompt_input = vec![
+            user_message("weather?"),
+            web_search_call("weather today"),
+            message("assistant", "sunny"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .emit_web_search(true)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][2]["tool_calls"],
+            json!([{
+                "id": "ws_1",
+                "type": "web_search",
+                "status": "completed",
+                "action": {"type": "search", "query": "weather today"},
+            }])
+        );
+    }
+
+    #[test]
+    fn skips_web_search_calls_by_default() {
+        let prompt_input = vec![
+            user_message("weather?"),
+            web_search_call("weather today"),
+            message("assistant", "sunny"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let roles: Vec<&str> = req.body["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .filter_map(|message| message["role"].as_str())
+            .collect();
+        assert_eq!(roles, vec!["system", "user", "assistant"]);
+        assert_eq!(req.body["messages"][2]["content"], json!("sunny"));
+    }
+
+    #[test]
+    fn skips_compaction_items() {
+        let prompt_input = vec![
+            ResponseItem::Compaction {
+                encrypted_content: "gAAAAB-opaque".to_string(),
+            },
+            user_message("continue"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "system", "content": "inst"},
+                {"role": "user", "content": "continue"},
+            ])
+        );
+    }
+
+    #[test]
+    fn assistant_prefill_appends_partial_assistant_message() {
+        let prompt_input = vec![user_message("write a haiku")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .assistant_prefill(Some("Autumn".to_string()))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][2],
+            json!({"role": "assistant", "content": "Autumn", "partial": true})
+        );
+    }
+
+    #[test]
+    fn assistant_prefill_extends_trailing_assistant_message() {
+        let prompt_input = vec![
+            user_message("write a haiku"),
+            message("assistant", "Autumn moonlight - "),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .assistant_prefill(Some("a worm".to_string()))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(req.message_count(), 3);
+        assert_eq!(
+            req.body["messages"][2],
+            json!({"role": "assistant", "content": "Autumn moonlight - a worm", "partial": true})
+        );
+    }
+
+    #[test]
+    fn with_model_only_swaps_the_model() {
+        let prompt_input = vec![user_message("hi")];
+        let tools = vec![read_file_tool()];
+        let base = ChatRequestBuilder::new("gpt-a", "inst", &prompt_input, &tools)
+            .enable_reasoning(true)
+            .reasoning_effort(ReasoningEffort::High)
+            .conversation_id(Some("conv-1".to_string()));
+
+        let other = base
+            .with_model("gpt-b")
+            .build(&provider())
+            .expect("request");
+        let original = base.build(&provider()).expect("request");
+
+        assert_eq!(original.body["model"], json!("gpt-a"));
+        assert_eq!(other.body["model"], json!("gpt-b"));
+        let mut other_body = other.body;
+        other_body["model"] = json!("gpt-a");
+        assert_eq!(other_body, original.body);
+        assert_eq!(other.headers, original.headers);
+    }
+
+    #[test]
+    fn autodetects_image_urls_in_plain_tool_output() {
+        let prompt_input = vec![
+            user_message("screenshot please"),
+            function_call("call-1", "screenshot", "{}"),
+            function_call_output("call-1", "https://example.com/shot.PNG?size=large"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .tool_output_autodetect_images(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"][3]["content"],
+            json!([{
+                "type": "image_url",
+                "image_url": {"url": "https://example.com/shot.PNG?size=large"},
+            }])
+        );
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"][3]["content"],
+            json!("https://example.com/shot.PNG?size=large")
+        );
+    }
+
+    #[test]
+    fn autodetect_leaves_non_image_tool_output_as_text() {
+        let prompt_input = vec![
+            user_message("fetch"),
+            function_call("call-1", "fetch", "{}"),
+            function_call_output("call-1", "see https://example.com/page.html"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .tool_output_autodetect_images(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"][3]["content"],
+            json!("see https://example.com/page.html")
+        );
+    }
+
+    #[test]
+    fn strict_mode_rejects_empty_input() {
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &[], &[])
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("empty input error");
+        assert_matches!(err, ApiError::EmptyInput);
+    }
+
+    #[test]
+    fn lenient_mode_sends_instructions_only_for_empty_input() {
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &[], &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"],
+            json!([{"role": "system", "content": "inst"}])
+        );
+    }
+
+    #[test]
+    fn reasoning_as_content_block_prepends_reasoning_part() {
+        let prompt_input = vec![
+            user_message("hi"),
+            reasoning("thinking it over"),
+            message("assistant", "hello"),
+            user_message("and now?"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .reasoning_as_content_block(true)
+            .build(&provider())
+            .expect("request");
+
+        let assistant = &req.body["messages"][2];
+        assert_eq!(
+            assistant["content"],
+            json!([
+                {"type": "reasoning", "text": "thinking it over"},
+                {"type": "text", "text": "hello"},
+            ])
+        );
+        assert_eq!(assistant.get("reasoning"), None);
+    }
+
+    #[test]
+    fn reasoning_content_block_keeps_message_key_order() {
+        let named_answer = ResponseItem::Message {
+            id: None,
+            role: "assistant".to_string(),
+            content: vec![ContentItem::OutputText {
+                text: "hello".to_string(),
+            }],
+            name: Some("helper".to_string()),
+            end_turn: None,
+        };
+        let prompt_input = vec![
+            user_message("hi"),
+            reasoning("thinking it over"),
+            named_answer,
+            user_message("and now?"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .reasoning_as_content_block(true)
+            .build(&provider())
+            .expect("request");
+
+        let keys: Vec<&str> = req.body["messages"][2]
+            .as_object()
+            .expect("assistant message")
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(keys, vec!["role", "content", "name"]);
+    }
+
+    #[test]
+    fn omit_system_message_drops_instructions() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "ignored", &prompt_input, &[])
+            .omit_system_message(true)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"],
+            json!([{"role": "user", "content": "hi"}])
+        );
+    }
+
+    #[test]
+    fn reasoning_join_separates_reasoning_items() {
+        let prompt_input = vec![
+            user_message("hi"),
+            reasoning("first thought"),
+            message("assistant", "answer"),
+            reasoning("second thought"),
+            user_message("thanks"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .reasoning_join("\n\n")
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][2]["reasoning"],
+            json!("first thought\n\nsecond thought")
+        );
+    }
+
+    #[test]
+    fn reasoning_join_separates_reasoning_on_combined_tool_calls() {
+        let prompt_input = vec![
+            user_message("read both"),
+            reasoning("need a"),
+            function_call("call-a", "read_file", r#"{"path":"a"}"#),
+            reasoning("need b"),
+            function_call("call-b", "read_file", r#"{"path":"b"}"#),
+            function_call_output("call-a", "alpha"),
+            function_call_output("call-b", "beta"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .reasoning_join(" | ")
+            .build(&provider())
+            .expect("request");
+
+        let assistant = &req.body["messages"][2];
+        assert_eq!(assistant["tool_calls"].as_array().map(Vec::len), Some(2));
+        assert_eq!(assistant["reasoning"], json!("need a | need b"));
+    }
+
+    #[test]
+    fn stream_option_merges_with_include_usage() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .include_usage(true)
+            .stream_option("chunk_size_hint", json!(64))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["stream_options"],
+            json!({"include_usage": true, "chunk_size_hint": 64})
+        );
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .stream(false)
+            .stream_option("chunk_size_hint", json!(64))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("stream_options"), None);
+    }
+
+    #[test]
+    fn strict_mode_rejects_duplicate_tool_call_ids() {
+        let prompt_input = vec![
+            user_message("read twice"),
+            function_call("call-1", "read_file", r#"{"path":"a"}"#),
+            function_call("call-1", "read_file", r#"{"path":"b"}"#),
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("duplicate id error");
+        assert_matches!(err, ApiError::DuplicateToolCallId { call_id } if call_id == "call-1");
+    }
+
+    #[test]
+    fn lenient_mode_keeps_first_tool_call_with_duplicate_id() {
+        let prompt_input = vec![
+            user_message("read twice"),
+            function_call("call-1", "read_file", r#"{"path":"a"}"#),
+            function_call("call-1", "read_file", r#"{"path":"b"}"#),
+            function_call_output("call-1", "alpha"),
+            function_call_output("call-1", "beta"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages");
+        let tool_calls = messages[2]["tool_calls"].as_array().expect("tool calls");
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(
+            tool_calls[0]["function"]["arguments"],
+            json!(r#"{"path":"a"}"#)
+        );
+        assert_eq!(messages.len(), 4);
+        assert_eq!(
+            messages[3],
+            json!({"role": "tool", "tool_call_id": "call-1", "content": "alpha"})
+        );
+    }
+
+    #[test]
+    fn subagent_header_name_can_be_overridden() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .session_source(Some(SessionSource::SubAgent(SubAgentSource::Review)))
+            .subagent_header_name("x-codex-subagent")
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.headers.get("x-codex-subagent"),
+            Some(&HeaderValue::from_static("review"))
+        );
+        assert_eq!(req.headers.get("x-openai-subagent"), None);
+    }
+
+    #[test]
+    fn serializes_each_service_tier() {
+        let prompt_input = vec![user_message("hi")];
+        for (tier, expected) in [
+            (ServiceTier::Auto, "auto"),
+            (ServiceTier::Default, "default"),
+            (ServiceTier::Flex, "flex"),
+            (ServiceTier::Priority, "priority"),
+        ] {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .service_tier(tier)
+                .build(&provider())
+                .expect("request");
+            assert_eq!(req.body["service_tier"], json!(expected));
+        }
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("service_tier"), None);
+    }
+
+    #[test]
+    fn emits_prediction_content_when_set() {
+        let prompt_input = vec![user_message("rename foo to bar")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .prediction(Some("fn bar() {}".to_string()))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["prediction"],
+            json!({"type": "content", "content": "fn bar() {}"})
+        );
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .prediction(None)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("prediction"), None);
+    }
+
+    #[test]
+    fn empty_structured_tool_output_falls_back_to_empty_string() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            ResponseItem::FunctionCallOutput {
+                call_id: "call-a".to_string(),
+                output: FunctionCallOutputPayload {
+                    content: String::new(),
+                    content_items: Some(Vec::new()),
+                    success: None,
+                },
+            },
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(req.body["messages"][3]["content"], json!(""));
+    }
+
+    #[test]
+    fn cache_up_to_last_user_marks_the_preceding_message() {
+        let prompt_input = vec![
+            user_message("long document"),
+            message("assistant", "summary"),
+            user_message("follow-up"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .cache_up_to_last_user(true)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][2]["content"],
+            json!([{
+                "type": "text",
+                "text": "summary",
+                "cache_control": {"type": "ephemeral"},
+            }])
+        );
+        assert_eq!(req.body["messages"][1]["content"], json!("long document"));
+        assert_eq!(req.body["messages"][3]["content"], json!("follow-up"));
+    }
+
+    #[test]
+    fn tool_defs_serialize_as_function_tools() {
+        let prompt_input = vec![user_message("hi")];
+        let raw_tools = vec![read_file_tool()];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &raw_tools)
+            .tool_defs(vec![ToolDef {
+                name: "list_dir".to_string(),
+                description: "List a directory".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {"path": {"type": "string"}},
+                    "required": ["path"],
+                }),
+            }])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["tools"],
+            json!([
+                read_file_tool(),
+                {
+                    "type": "function",
+                    "function": {
+                        "name": "list_dir",
+                        "description": "List a directory",
+                        "parameters": {
+                            "type": "object",
+                            "properties": {"path": {"type": "string"}},
+                            "required": ["path"],
+                        },
+                    },
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn max_images_counts_user_and_tool_images() {
+        let prompt_input = vec![
+            user_image_message("https://example.com/a.png"),
+            function_call("call-a", "screenshot", "{}"),
+            ResponseItem::FunctionCallOutput {
+                call_id: "call-a".to_string(),
+                output: FunctionCallOutputPayload {
+                    content: String::new(),
+                    content_items: Some(vec![FunctionCallOutputContentItem::InputImage {
+                        image_url: "https://example.com/b.png".to_string(),
+                    }]),
+                    success: None,
+                },
+            },
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_images(Some(1))
+            .build(&provider())
+            .err()
+            .expect("too many images");
+        assert_matches!(err, ApiError::TooManyImages { count: 2, limit: 1 });
+
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_images(Some(2))
+            .build(&provider())
+            .expect("request within the limit");
+    }
+
+    #[test]
+    fn max_images_counts_autodetected_tool_images() {
+        let prompt_input = vec![
+            user_image_message("https://example.com/a.png"),
+            function_call("call-a", "screenshot", "{}"),
+            function_call_output("call-a", "https://example.com/b.png"),
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .tool_output_autodetect_images(true)
+            .max_images(Some(1))
+            .build(&provider())
+            .err()
+            .expect("too many images");
+        assert_matches!(err, ApiError::TooManyImages { count: 2, limit: 1 });
+
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_images(Some(1))
+            .build(&provider())
+            .expect("plain text output is not an image");
+    }
+
+    #[test]
+    fn force_structured_content_wraps_text_in_parts() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-a", "done"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .force_structured_content(true)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(req.body["messages"][0]["content"], json!("inst"));
+        assert_eq!(
+            req.body["messages"][1]["content"],
+            json!([{"type": "text", "text": "run it"}])
+        );
+        assert_eq!(
+            req.body["messages"][3]["content"],
+            json!([{"type": "text", "text": "done"}])
+        );
+    }
+
+    #[test]
+    fn force_structured_content_keeps_empty_tool_output_as_parts() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            ResponseItem::FunctionCallOutput {
+                call_id: "call-a".to_string(),
+                output: FunctionCallOutputPayload {
+                    content_items: Some(Vec::new()),
+                    ..Default::default()
+                },
+            },
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .force_structured_content(true)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["messages"][3]["content"],
+            json!([{"type": "text", "text": ""}])
+        );
+    }
+
+    #[test]
+    fn store_flag_reflects_configuration() {
+        let prompt_input = vec![user_message("hi")];
+
+        for store in [true, false] {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .store(Some(store))
+                .build(&provider())
+                .expect("request");
+            assert_eq!(req.body["store"], json!(store));
+        }
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("store"), None);
+    }
+
+    #[test]
+    fn tool_name_rewrite_applies_to_tools_calls_and_choice() {
+        let prompt_input = vec![
+            user_message("read it"),
+            function_call("call-a", "read_file", "{}"),
+            function_call_output("call-a", "contents"),
+        ];
+        let tools = vec![read_file_tool()];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .tool_choice(ToolChoice::Function("read_file".to_string()))
+            .tool_name_rewrite(Box::new(|name: &str| format!("srv1__{name}")))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["tools"][0]["function"]["name"],
+            json!("srv1__read_file")
+        );
+        assert_eq!(
+            req.body["messages"][2]["tool_calls"][0]["function"]["name"],
+            json!("srv1__read_file")
+        );
+        assert_eq!(
+            req.body["tool_choice"]["function"]["name"],
+            json!("srv1__read_file")
+        );
+    }
+
+    #[test]
+    fn payload_keys_follow_a_fixed_order() {
+        let prompt_input = vec![user_message("hi")];
+        let build = || {
+            let mut extra = Map::new();
+            extra.insert("zeta".to_string(), json!(1));
+            extra.insert("alpha".to_string(), json!(2));
+            extra.insert("temperature".to_string(), json!(0.5));
+            ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .temperature(Some(1.0))
+                .seed(Some(7))
+                .user_tag(Some("tester".to_string()))
+                .extra_body(extra)
+                .build(&provider())
+                .expect("request")
+        };
+
+        let req = build();
+        let keys: Vec<&str> = req
+            .body
+            .as_object()
+            .expect("object body")
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "model",
+                "messages",
+                "stream",
+                "tools",
+                "temperature",
+                "seed",
+                "user",
+                "alpha",
+                "zeta",
+            ]
+        );
+        assert_eq!(req.body["temperature"], json!(0.5));
+        assert_eq!(req.body.to_string(), build().body.to_string());
+    }
+
+    #[test]
+    fn max_instructions_bytes_rejects_oversized_instructions() {
+        let prompt_input = vec![user_message("hi")];
+        let instructions = "x".repeat(64);
+
+        let err = ChatRequestBuilder::new("gpt-test", &instructions, &prompt_input, &[])
+            .max_instructions_bytes(Some(32))
+            .build(&provider())
+            .err()
+            .expect("instructions too large");
+        assert_matches!(
+            err,
+            ApiError::InstructionsTooLarge {
+                bytes: 64,
+                limit: 32
+            }
+        );
+
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_instructions_bytes(Some(32))
+            .build(&provider())
+            .expect("instructions within the limit");
+    }
+
+    #[test]
+    fn function_arguments_as_object_parses_valid_json() {
+        let prompt_input = vec![
+            user_message("read"),
+            function_call("call-a", "read_file", r#"{"path":"a.txt"}"#),
+            function_call("call-b", "read_file", "{not json"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .function_arguments_as_object(true)
+            .build(&provider())
+            .expect("request");
+
+        let tool_calls = &req.body["messages"][2]["tool_calls"];
+        assert_eq!(
+            tool_calls[0]["function"]["arguments"],
+            json!({"path": "a.txt"})
+        );
+        assert_eq!(tool_calls[1]["function"]["arguments"], json!("{not json"));
+    }
+
+    #[test]
+    fn omit_empty_tools_drops_tools_and_tool_choice() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .tool_choice(ToolChoice::Auto)
+            .omit_empty_tools(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("tools"), None);
+        assert_eq!(req.body.get("tool_choice"), None);
+
+        let tools = vec![read_file_tool()];
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .omit_empty_tools(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["tools"], json!([read_file_tool()]));
+    }
+
+    #[test]
+    fn exposes_emitted_tool_call_ids() {
+        let prompt_input = vec![
+            user_message("read both"),
+            function_call("call-a", "read_file", r#"{"path":"a.txt"}"#),
+            function_call("call-b", "read_file", r#"{"path":"b.txt"}"#),
+            function_call_output("call-a", "a"),
+            function_call_output("call-b", "b"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.tool_call_ids,
+            vec!["call-a".to_string(), "call-b".to_string()]
+        );
+    }
+
+    #[test]
+    fn reasoning_max_tokens_only_emitted_with_reasoning_enabled() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .enable_reasoning(true)
+            .reasoning_max_tokens(Some(2048))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["reasoning"],
+            json!({"enabled": true, "max_tokens": 2048})
+        );
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .reasoning_max_tokens(Some(2048))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("reasoning"), None);
+    }
+
+    #[test]
+    fn clear_thinking_reflects_configuration() {
+        let prompt_input = vec![user_message("hi")];
+
+        for clear in [true, false] {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .enable_reasoning(true)
+                .clear_thinking(clear)
+                .build(&provider())
+                .expect("request");
+            assert_eq!(req.body["thinking"]["clear_thinking"], json!(clear));
+        }
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .enable_reasoning(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("thinking"), None);
+    }
+
+    #[test]
+    fn chat_template_kwargs_merge_with_thinking_default() {
+        let prompt_input = vec![user_message("hi")];
+        let mut kwargs = Map::new();
+        kwargs.insert("add_generation_prompt".to_string(), json!(true));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .enable_reasoning(true)
+            .chat_template_kwargs(kwargs.clone())
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["chat_template_kwargs"],
+            json!({"thinking": true, "add_generation_prompt": true})
+        );
+
+        kwargs.insert("thinking".to_string(), json!(false));
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .chat_template_kwargs(kwargs)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["chat_template_kwargs"],
+            json!({"add_generation_prompt": true, "thinking": false})
+        );
+    }
+
+    #[test]
+    fn strict_mode_checks_data_uri_image_types() {
+        let prompt_input = vec![user_image_message("data:image/webp;base64,UklGRg==")];
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .expect("webp is supported");
+
+        let prompt_input = vec![user_image_message("data:image/bmp;base64,Qk0=")];
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("bmp is unsupported");
+        assert_matches!(err, ApiError::UnsupportedImageMime { mime } if mime == "image/bmp");
+
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("lenient mode accepts bmp");
+    }
+
+    #[test]
+    fn joins_beta_features_into_one_header() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .beta_features(vec!["assistants=v2".to_string(), "realtime=v1".to_string()])
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.headers.get("openai-beta"),
+            Some(&HeaderValue::from_static("assistants=v2,realtime=v1"))
+        );
+    }
+
+    #[test]
+    fn strict_tools_marks_function_tools_strict() {
+        let prompt_input = vec![user_message("hi")];
+        let tools = vec![
+            json!({
+                "type": "function",
+                "function": {
+                    "name": "read_file",
+                    "parameters": {"type": "object", "properties": {}},
+                },
+            }),
+            json!({
+                "type": "function",
+                "function": {"name": "loose", "strict": false},
+            }),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .strict_tools(true)
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body["tools"][0]["function"],
+            json!({
+                "name": "read_file",
+                "parameters": {
+                    "type": "object",
+                    "properties": {},
+                    "additionalProperties": false,
+                },
+                "strict": true,
+            })
+        );
+        assert_eq!(req.body["tools"][1]["function"]["strict"], json!(false));
+    }
+
+    #[test]
+    fn strict_mode_rejects_reasoning_anchored_to_user_message() {
+        let prompt_input = vec![
+            user_message("first"),
+            reasoning("orphaned thought"),
+            user_message("second"),
+            message("assistant", "answer"),
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("reasoning on user message");
+        assert_matches!(err, ApiError::ReasoningOnUserMessage { index: 2 });
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("lenient request");
+        assert_eq!(req.body["messages"][1].get("reasoning"), None);
+    }
+
+    #[test]
+    fn strict_mode_accepts_reasoning_from_interrupted_turn() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-a", "done"),
+            reasoning("next I should"),
+            user_message("stop, do something else"),
+            message("assistant", "ok"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .expect("interrupted turn is valid");
+
+        let messages = req.body["messages"].as_array().expect("messages");
+        assert_eq!(messages.len(), 6);
+        assert!(messages.iter().all(|msg| msg.get("reasoning").is_none()));
+    }
+
+    fn audio_config() -> AudioConfig {
+        AudioConfig {
+            voice: "alloy".to_string(),
+            format: "wav".to_string(),
+        }
+    }
+
+    #[test]
+    fn emits_modalities_and_audio_output() {
+        let prompt_input = vec![user_message("say hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .modalities(vec![Modality::Text, Modality::Audio])
+            .audio_output(Some(audio_config()))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(req.body["modalities"], json!(["text", "audio"]));
+        assert_eq!(
+            req.body["audio"],
+            json!({"voice": "alloy", "format": "wav"})
+        );
+    }
+
+    #[test]
+    fn rejects_audio_output_without_audio_modality() {
+        let prompt_input = vec![user_message("say hi")];
+
+        let result = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .modalities(vec![Modality::Text])
+            .audio_output(Some(audio_config()))
+            .build(&provider());
+
+        assert_eq!(
+            invalid_request_message(result),
+            "audio output requires the audio modality"
+        );
+    }
+
+    #[test]
+    fn max_tools_rejects_oversized_catalogs() {
+        let prompt_input = vec![user_message("hi")];
+        let tools = vec![
+            read_file_tool(),
+            json!({"type": "function", "function": {"name": "write_file"}}),
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .max_tools(Some(1))
+            .build(&provider())
+            .err()
+            .expect("too many tools");
+        assert_matches!(err, ApiError::TooManyTools { count: 2, limit: 1 });
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .max_tools(Some(2))
+            .build(&provider())
+            .expect("request within the limit");
+        assert_eq!(req.body["tools"], json!(tools));
+    }
+
+    #[test]
+    fn dedup_assistant_window_controls_how_far_back_duplicates_are_dropped() {
+        let prompt_input = vec![
+            user_message("q1"),
+            message("assistant", "A"),
+            user_message("q2"),
+            message("assistant", "A"),
+            user_message("q3"),
+            message("assistant", "B"),
+            user_message("q4"),
+            message("assistant", "A"),
+        ];
+
+        for (window, expected) in [
+            (0, vec!["A", "A", "B", "A"]),
+            (1, vec!["A", "B", "A"]),
+            (2, vec!["A", "B"]),
+        ] {
+            let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .dedup_assistant_window(window)
+                .build(&provider())
+                .expect("request");
+            let assistant_texts: Vec<&str> = req.body["messages"]
+                .as_array()
+                .expect("messages array")
+                .iter()
+                .filter(|msg| msg["role"] == "assistant")
+                .filter_map(|msg| msg["content"].as_str())
+                .collect();
+            assert_eq!(assistant_texts, expected, "window {window}");
+        }
+    }
+
+    #[test]
+    fn strict_mode_requires_tool_calls_to_be_resolved() {
+        let resolved = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            function_call_output("call-a", "done"),
+            message("assistant", "ran it"),
+        ];
+        ChatRequestBuilder::new("gpt-test", "inst", &resolved, &[])
+            .strict(true)
+            .build(&provider())
+            .expect("resolved call");
+
+        let dangling = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
+            message("assistant", "never ran"),
+            user_message("and now?"),
+        ];
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &dangling, &[])
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("unresolved call");
+        assert_matches!(err, ApiError::UnresolvedToolCall { call_id } if call_id == "call-a");
+    }
+
+    #[test]
+    fn strict_mode_allows_a_trailing_pending_tool_call() {
+        let prompt_input = vec![
+            user_message("run both"),
+            function_call("call-a", "shell", "{}"),
+            function_call("call-b", "shell", "{}"),
+            function_call_output("call-a", "done"),
+        ];
+
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .strict(true)
+            .build(&provider())
+            .expect("pending call in the final turn");
+    }
+
+    #[test]
+    fn summary_reports_counts_without_contents() {
+        let prompt_input = vec![user_message("secret"), message("assistant", "reply")];
+        let tools = vec![read_file_tool()];
 
-        let mut headers = build_conversation_headers(self.conversation_id);
-        if let Some(subagent) = subagent_header(&self.session_source) {
-            insert_header(&mut headers, "x-openai-subagent", &subagent);
-        }
+        let summary = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .enable_reasoning(true)
+            .summary();
 
-        Ok(ChatRequest {
-            body: payload,
-            headers,
-        })
+        assert_eq!(
+            summary,
+            "model=gpt-test input_items=2 tools=1 reasoning=true"
+        );
     }
-}
 
-fn push_tool_call_message(messages: &mut Vec<Value>, tool_call: Value, reasoning: Option<&str>) {
-    // Chat Completions requires that tool calls are grouped into a single assistant message
-    // (with `tool_calls: [...]`) followed by tool role responses.
-    if let Some(Value::Object(obj)) = messages.last_mut()
-        && obj.get("role").and_then(Value::as_str) == Some("assistant")
-        && obj.get("content").is_some_and(Value::is_null)
-        && let Some(tool_calls) = obj.get_mut("tool_calls").and_then(Value::as_array_mut)
-    {
-        tool_calls.push(tool_call);
-        if let Some(reasoning) = reasoning {
-            if let Some(Value::String(existing)) = obj.get_mut("reasoning") {
-                if !existing.is_empty() {
-                    existing.push('\n');
-                }
-                existing.push_str(reasoning);
-            } else {
-                obj.insert(
-                    "reasoning".to_string(),
-                    Value::String(reasoning.to_string()),
-                );
-            }
-        }
-        return;
+    fn ghost_snapshot(id: &str) -> ResponseItem {
+        serde_json::from_value(json!({
+            "type": "ghost_snapshot",
+            "ghost_commit": {
+                "id": id,
+                "parent": "def456",
+                "preexisting_untracked_files": [],
+                "preexisting_untracked_dirs": [],
+            },
+        }))
+        .expect("ghost snapshot")
     }
 
-    let mut msg = json!({
-        "role": "assistant",
-        "content": null,
-        "tool_calls": [tool_call],
-    });
-    if let Some(reasoning) = reasoning
-        && let Some(obj) = msg.as_object_mut()
-    {
-        obj.insert("reasoning".to_string(), json!(reasoning));
-    }
-    messages.push(msg);
-}
+    #[test]
+    fn emits_ghost_snapshots_as_system_notes_when_enabled() {
+        let prompt_input = vec![
+            user_message("edit it"),
+            ghost_snapshot("abc123"),
+            user_message("undo"),
+        ];
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::provider::RetryConfig;
-    use crate::provider::WireApi;
-    use codex_protocol::models::FunctionCallOutputPayload;
-    use codex_protocol::protocol::SessionSource;
-    use codex_protocol::protocol::SubAgentSource;
-    use http::HeaderValue;
-    use pretty_assertions::assert_eq;
-    use std::time::Duration;
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .include_ghost_snapshots(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "system", "content": "inst"},
+                {"role": "user", "content": "edit it"},
+                {
+                    "role": "system",
+                    "content": "Workspace snapshot abc123 (parent def456) was taken at this point.",
+                },
+                {"role": "user", "content": "undo"},
+            ])
+        );
 
-    fn provider() -> Provider {
-        Provider {
-            name: "openai".to_string(),
-            base_url: "https://api.openai.com/v1".to_string(),
-            query_params: None,
-            wire: WireApi::Chat,
-            headers: HeaderMap::new(),
-            retry: RetryConfig {
-                max_attempts: 1,
-                base_delay: Duration::from_millis(10),
-                retry_429: false,
-                retry_5xx: true,
-                retry_transport: true,
-            },
-            stream_idle_timeout: Duration::from_secs(1),
-        }
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.message_count(), 2);
     }
 
     #[test]
-    fn attaches_conversation_and_subagent_headers() {
-        let prompt_input = vec![ResponseItem::Message {
-            id: None,
-            role: "user".to_string(),
-            content: vec![ContentItem::InputText {
-                text: "hi".to_string(),
-            }],
-            end_turn: None,
-        }];
+    fn prompt_cache_key_is_serialized_when_set() {
+        let prompt_input = vec![user_message("hi")];
+
         let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
-            .conversation_id(Some("conv-1".into()))
-            .session_source(Some(SessionSource::SubAgent(SubAgentSource::Review)))
+            .prompt_cache_key(Some("repo-main".to_string()))
             .build(&provider())
             .expect("request");
+        assert_eq!(req.body["prompt_cache_key"], json!("repo-main"));
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("prompt_cache_key"), None);
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .prompt_cache_key(Some(String::new()))
+            .build(&provider())
+            .err()
+            .expect("empty cache key");
+        assert_matches!(err, ApiError::InvalidRequest { .. });
+    }
+
+    fn template_vars() -> HashMap<String, String> {
+        HashMap::from([("repo".to_string(), "codex".to_string())])
+    }
+
+    #[test]
+    fn template_vars_substitute_instruction_placeholders() {
+        let prompt_input = vec![user_message("hi")];
 
+        let req = ChatRequestBuilder::new(
+            "gpt-test",
+            "You work on {{repo}} ({{ repo }}); see {{missing}}.",
+            &prompt_input,
+            &[],
+        )
+        .template_vars(template_vars())
+        .build(&provider())
+        .expect("request");
         assert_eq!(
-            req.headers.get("session_id"),
-            Some(&HeaderValue::from_static("conv-1"))
+            req.body["messages"][0]["content"],
+            json!("You work on codex (codex); see {{missing}}.")
         );
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_template_variables() {
+        let prompt_input = vec![user_message("hi")];
+
+        let err = ChatRequestBuilder::new("gpt-test", "see {{missing}}", &prompt_input, &[])
+            .template_vars(template_vars())
+            .strict(true)
+            .build(&provider())
+            .err()
+            .expect("unknown variable");
+        assert_matches!(err, ApiError::InvalidRequest { .. });
+
+        let req = ChatRequestBuilder::new("gpt-test", "on {{repo}}", &prompt_input, &[])
+            .template_vars(template_vars())
+            .strict(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["messages"][0]["content"], json!("on codex"));
+    }
+
+    #[test]
+    fn safety_identifier_sets_header_and_body_field() {
+        let prompt_input = vec![user_message("hi")];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .safety_identifier(Some("9f86d081884c7d65".to_string()))
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["safety_identifier"], json!("9f86d081884c7d65"));
         assert_eq!(
-            req.headers.get("x-openai-subagent"),
-            Some(&HeaderValue::from_static("review"))
+            req.headers.get("x-safety-identifier"),
+            Some(&HeaderValue::from_static("9f86d081884c7d65"))
         );
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body.get("safety_identifier"), None);
+        assert_eq!(req.headers.get("x-safety-identifier"), None);
+
+        for identifier in ["", "user@example.com"] {
+            let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+                .safety_identifier(Some(identifier.to_string()))
+                .build(&provider())
+                .err()
+                .expect("invalid safety identifier");
+            assert_matches!(err, ApiError::InvalidRequest { .. });
+        }
     }
 
     #[test]
-    fn groups_consecutive_tool_calls_into_a_single_assistant_message() {
+    fn tools_unsupported_downgrades_calls_and_outputs_to_text() {
         let prompt_input = vec![
-            ResponseItem::Message {
-                id: None,
-                role: "user".to_string(),
-                content: vec![ContentItem::InputText {
-                    text: "read these".to_string(),
-                }],
-                end_turn: None,
-            },
-            ResponseItem::FunctionCall {
+            user_message("read both"),
+            message("assistant", "Reading."),
+            function_call("call-a", "read_file", r#"{"path":"a"}"#),
+            function_call("call-b", "read_file", r#"{"path":"b"}"#),
+            function_call_output("call-a", "alpha"),
+            function_call_output("call-b", "beta"),
+        ];
+        let tools = vec![read_file_tool()];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &tools)
+            .tool_choice(ToolChoice::Auto)
+            .tools_unsupported(true)
+            .build(&provider())
+            .expect("request");
+        assert_eq!(
+            req.body["messages"],
+            json!([
+                {"role": "system", "content": "inst"},
+                {"role": "user", "content": "read both"},
+                {
+                    "role": "assistant",
+                    "content": "Reading.\nCalled read_file (call-a) with {\"path\":\"a\"}\nCalled read_file (call-b) with {\"path\":\"b\"}",
+                },
+                {
+                    "role": "user",
+                    "content": "Output of read_file (call-a):\nalpha\nOutput of read_file (call-b):\nbeta",
+                },
+            ])
+        );
+        assert_eq!(req.body.get("tools"), None);
+        assert_eq!(req.body.get("tool_choice"), None);
+    }
+
+    #[test]
+    fn tools_unsupported_keeps_reasoning_on_downgraded_calls() {
+        let prompt_input = vec![
+            user_message("read it"),
+            reasoning("need the file"),
+            function_call("call-a", "read_file", r#"{"path":"a"}"#),
+            function_call_output("call-a", "alpha"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .tools_unsupported(true)
+            .build(&provider())
+            .expect("request");
+
+        let assistant = &req.body["messages"][2];
+        assert_eq!(assistant["role"], json!("assistant"));
+        assert_eq!(assistant["reasoning"], json!("need the file"));
+    }
+
+    #[test]
+    fn tools_unsupported_downgrades_every_call_kind_and_keeps_user_turns_separate() {
+        let prompt_input = vec![
+            user_message("patch and list"),
+            ResponseItem::CustomToolCall {
                 id: None,
-                name: "read_file".to_string(),
-                arguments: r#"{"path":"a.txt"}"#.to_string(),
-                call_id: "call-a".to_string(),
+                status: None,
+                call_id: "call-patch".to_string(),
+                name: "apply_patch".to_string(),
+                input: "*** Begin Patch".to_string(),
             },
-            ResponseItem::FunctionCall {
+            ResponseItem::LocalShellCall {
                 id: None,
-                name: "read_file".to_string(),
-                arguments: r#"{"path":"b.txt"}"#.to_string(),
-                call_id: "call-b".to_string(),
+                call_id: Some("call-ls".to_string()),
+                status: LocalShellStatus::Completed,
+                action: LocalShellAction::Exec(LocalShellExecAction {
+                    command: vec!["ls".to_string()],
+                    timeout_ms: None,
+                    working_directory: None,
+                    env: None,
+                    user: None,
+                }),
             },
-            ResponseItem::FunctionCall {
-                id: None,
-                name: "read_file".to_string(),
-                arguments: r#"{"path":"c.txt"}"#.to_string(),
-                call_id: "call-c".to_string(),
+            web_search_call("weather today"),
+            ResponseItem::CustomToolCallOutput {
+                call_id: "call-patch".to_string(),
+                output: "patched".to_string(),
             },
+            function_call_output("call-ls", "a.rs"),
+            user_message("thanks"),
+        ];
+
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .emit_web_search(true)
+            .tools_unsupported(true)
+            .build(&provider())
+            .expect("request");
+
+        let messages = req.body["messages"].as_array().expect("messages");
+        assert_eq!(messages.len(), 5);
+        let calls: Vec<&str> = messages[2]["content"]
+            .as_str()
+            .expect("assistant text")
+            .lines()
+            .collect();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(
+            calls[0],
+            "Called apply_patch (call-patch) with *** Begin Patch"
+        );
+        assert!(
+            calls[1].starts_with(
+                r#"Called local_shell (call-ls) with {"type":"exec","command":["ls"]"#
+            )
+        );
+        assert_eq!(
+            calls[2],
+            r#"Called web_search (ws_1) with {"type":"search","query":"weather today"}"#
+        );
+        assert_eq!(
+            messages[3],
+            json!({
+                "role": "user",
+                "content": "Output of apply_patch (call-patch):\npatched\nOutput of local_shell (call-ls):\na.rs",
+            })
+        );
+        assert_eq!(messages[4], json!({"role": "user", "content": "thanks"}));
+    }
+
+    #[test]
+    fn max_image_bytes_rejects_large_data_uris() {
+        let oversized = vec![user_image_message(&format!(
+            "data:image/png;base64,{}\n{}==",
+            "A".repeat(12),
+            "A".repeat(2)
+        ))];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &oversized, &[])
+            .max_image_bytes(Some(8))
+            .build(&provider())
+            .err()
+            .expect("image too large");
+        assert_matches!(
+            err,
+            ApiError::ImageTooLarge {
+                bytes: 10,
+                limit: 8
+            }
+        );
+
+        let remote = vec![user_image_message("https://example.com/huge.png")];
+        ChatRequestBuilder::new("gpt-test", "inst", &remote, &[])
+            .max_image_bytes(Some(8))
+            .build(&provider())
+            .expect("http images are exempt");
+    }
+
+    #[test]
+    fn max_image_bytes_checks_autodetected_tool_images() {
+        let prompt_input = vec![
+            user_message("screenshot please"),
+            function_call("call-a", "screenshot", "{}"),
+            function_call_output(
+                "call-a",
+                &format!("data:image/png;base64,{}", "A".repeat(12)),
+            ),
+        ];
+
+        let err = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .tool_output_autodetect_images(true)
+            .max_image_bytes(Some(8))
+            .build(&provider())
+            .err()
+            .expect("image too large");
+        assert_matches!(err, ApiError::ImageTooLarge { bytes: 9, limit: 8 });
+
+        ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .max_image_bytes(Some(8))
+            .build(&provider())
+            .expect("plain text output is not an image");
+    }
+
+    #[test]
+    fn mark_tool_errors_flags_failed_tool_outputs() {
+        let prompt_input = vec![
+            user_message("run it"),
+            function_call("call-a", "shell", "{}"),
             ResponseItem::FunctionCallOutput {
                 call_id: "call-a".to_string(),
                 output: FunctionCallOutputPayload {
-                    content: "A".to_string(),
-                    ..Default::default()
-                },
-            },
-            ResponseItem::FunctionCallOutput {
-                call_id: "call-b".to_string(),
-                output: FunctionCallOutputPayload {
-                    content: "B".to_string(),
-                    ..Default::default()
-                },
-            },
-            ResponseItem::FunctionCallOutput {
-                call_id: "call-c".to_string(),
-                output: FunctionCallOutputPayload {
-                    content: "C".to_string(),
-                    ..Default::default()
+                    content: "command not found".to_string(),
+                    content_items: None,
+                    success: Some(false),
                 },
             },
+            function_call("call-b", "shell", "{}"),
+            function_call_output("call-b", "ok"),
         ];
 
         let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .mark_tool_errors(true)
             .build(&provider())
             .expect("request");
-
-        let messages = req
-            .body
-            .get("messages")
-            .and_then(|v| v.as_array())
-            .expect("messages array");
-        // system + user + assistant(tool_calls=[...]) + 3 tool outputs
-        assert_eq!(messages.len(), 6);
-
-        assert_eq!(messages[0]["role"], "system");
-        assert_eq!(messages[1]["role"], "user");
-
-        let tool_calls_msg = &messages[2];
-        assert_eq!(tool_calls_msg["role"], "assistant");
-        assert_eq!(tool_calls_msg["content"], serde_json::Value::Null);
-        let tool_calls = tool_calls_msg["tool_calls"]
+        let tool_messages: Vec<&Value> = req.body["messages"]
             .as_array()
-            .expect("tool_calls array");
-        assert_eq!(tool_calls.len(), 3);
-        assert_eq!(tool_calls[0]["id"], "call-a");
-        assert_eq!(tool_calls[1]["id"], "call-b");
-        assert_eq!(tool_calls[2]["id"], "call-c");
+            .expect("messages array")
+            .iter()
+            .filter(|msg| msg["role"] == "tool")
+            .collect();
+        assert_eq!(
+            tool_messages,
+            vec![
+                &json!({
+                    "role": "tool",
+                    "tool_call_id": "call-a",
+                    "content": "command not found",
+                    "is_error": true,
+                }),
+                &json!({"role": "tool", "tool_call_id": "call-b", "content": "ok"}),
+            ]
+        );
 
-        assert_eq!(messages[3]["role"], "tool");
-        assert_eq!(messages[3]["tool_call_id"], "call-a");
-        assert_eq!(messages[4]["role"], "tool");
-        assert_eq!(messages[4]["tool_call_id"], "call-b");
-        assert_eq!(messages[5]["role"], "tool");
-        assert_eq!(messages[5]["tool_call_id"], "call-c");
+        let req = ChatRequestBuilder::new("gpt-test", "inst", &prompt_input, &[])
+            .build(&provider())
+            .expect("request");
+        assert_eq!(req.body["messages"][3].get("is_error"), None);
+    }
+
+    #[test]
+    fn builder_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ChatRequestBuilder<'static>>();
     }
 }
@@ -0,0 +1,169 @@
+use crate::error::ApiError;
+use crate::provider::Provider;
+use crate::requests::headers::build_conversation_headers;
+use http::HeaderMap;
+use serde_json::Value;
+use serde_json::json;
+
+/// Encoding of the returned embedding vectors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    Base64,
+}
+
+impl EncodingFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            EncodingFormat::Float => "float",
+            EncodingFormat::Base64 => "base64",
+        }
+    }
+}
+
+/// Assembled request body plus headers for an embeddings request.
+pub struct EmbeddingsRequest {
+    pub body: Value,
+    pub headers: HeaderMap,
+}
+
+pub struct EmbeddingsRequestBuilder<'a> {
+    model: &'a str,
+    input: Vec<String>,
+    dimensions: Option<u32>,
+    encoding_format: Option<EncodingFormat>,
+    conversation_id: Option<String>,
+}
+
+impl<'a> EmbeddingsRequestBuilder<'a> {
+    pub fn new(model: &'a str, input: Vec<String>) -> Self {
+        Self {
+            model,
+            input,
+            dimensions: None,
+            encoding_format: None,
+            conversation_id: None,
+        }
+    }
+
+    /// Truncates the returned vectors to `dimensions`, for models that support it.
+    pub fn dimensions(mut self, dimensions: Option<u32>) -> Self {
+        self.dimensions = dimensions;
+        self
+    }
+
+    pub fn encoding_format(mut self, format: EncodingFormat) -> Self {
+        self.encoding_format = Some(format);
+        self
+    }
+
+    pub fn conversation_id(mut self, id: Option<String>) -> Self {
+        self.conversation_id = id;
+        self
+    }
+
+    pub fn build(self, _provider: &Provider) -> Result<EmbeddingsRequest, ApiError> {
+        if self.input.is_empty() {
+            return Err(ApiError::InvalidRequest {
+                message: "embeddings input must not be empty".to_string(),
+            });
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "input": self.input,
+        });
+        if let Some(obj) = body.as_object_mut() {
+            if let Some(dimensions) = self.dimensions {
+                obj.insert("dimensions".to_string(), json!(dimensions));
+            }
+            if let Some(format) = self.encoding_format {
+                obj.insert("encoding_format".to_string(), json!(format.as_str()));
+            }
+        }
+
+        Ok(EmbeddingsRequest {
+            body,
+            headers: build_conversation_headers(self.conversation_id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::RetryConfig;
+    use crate::provider::WireApi;
+    use assert_matches::assert_matches;
+    use http::HeaderValue;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn provider() -> Provider {
+        Provider {
+            name: "openai".to_string(),
+            base_url: "https://api.openai.com/v1".to_string(),
+            query_params: None,
+            wire: WireApi::Chat,
+            headers: HeaderMap::new(),
+            retry: RetryConfig {
+                max_attempts: 1,
+                base_delay: Duration::from_millis(10),
+                retry_429: false,
+                retry_5xx: true,
+                retry_transport: true,
+            },
+            stream_idle_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn single_input_is_serialized_as_an_array() {
+        let req = EmbeddingsRequestBuilder::new("text-embedding-3-small", vec!["hi".to_string()])
+            .conversation_id(Some("conv-1".to_string()))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body,
+            json!({"model": "text-embedding-3-small", "input": ["hi"]})
+        );
+        assert_eq!(
+            req.headers.get("session_id"),
+            Some(&HeaderValue::from_static("conv-1"))
+        );
+    }
+
+    #[test]
+    fn multi_input_includes_optional_fields() {
+        let req = EmbeddingsRequestBuilder::new(
+            "text-embedding-3-small",
+            vec!["first".to_string(), "second".to_string()],
+        )
+        .dimensions(Some(256))
+        .encoding_format(EncodingFormat::Base64)
+        .build(&provider())
+        .expect("request");
+
+        assert_eq!(
+            req.body,
+            json!({
+                "model": "text-embedding-3-small",
+                "input": ["first", "second"],
+                "dimensions": 256,
+                "encoding_format": "base64",
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_empty_input() {
+        let err = EmbeddingsRequestBuilder::new("text-embedding-3-small", Vec::new())
+            .build(&provider())
+            .err()
+            .expect("empty input error");
+
+        assert_matches!(err, ApiError::InvalidRequest { .. });
+    }
+}
@@ -0,0 +1,203 @@
+use crate::error::ApiError;
+use crate::provider::Provider;
+use crate::requests::headers::build_conversation_headers;
+use http::HeaderMap;
+use serde_json::Value;
+use serde_json::json;
+
+/// Image sizes accepted by the images endpoint across the supported models.
+const SUPPORTED_IMAGE_SIZES: [&str; 8] = [
+    "256x256",
+    "512x512",
+    "1024x1024",
+    "1024x1536",
+    "1536x1024",
+    "1024x1792",
+    "1792x1024",
+    "auto",
+];
+
+/// Largest number of images a single request may ask for.
+const MAX_IMAGES_PER_REQUEST: u32 = 10;
+
+/// How generated images are returned.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageResponseFormat {
+    #[default]
+    Url,
+    B64Json,
+}
+
+impl ImageResponseFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            ImageResponseFormat::Url => "url",
+            ImageResponseFormat::B64Json => "b64_json",
+        }
+    }
+}
+
+/// Assembled request body plus headers for an image-generation request.
+pub struct ImageRequest {
+    pub body: Value,
+    pub headers: HeaderMap,
+}
+
+pub struct ImageRequestBuilder<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    size: Option<&'a str>,
+    n: Option<u32>,
+    response_format: Option<ImageResponseFormat>,
+    conversation_id: Option<String>,
+}
+
+impl<'a> ImageRequestBuilder<'a> {
+    pub fn new(model: &'a str, prompt: &'a str) -> Self {
+        Self {
+            model,
+            prompt,
+            size: None,
+            n: None,
+            response_format: None,
+            conversation_id: None,
+        }
+    }
+
+    /// Requested dimensions as `WIDTHxHEIGHT`, or `auto`.
+    pub fn size(mut self, size: Option<&'a str>) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Number of images to generate, between 1 and 10.
+    pub fn n(mut self, n: Option<u32>) -> Self {
+        self.n = n;
+        self
+    }
+
+    pub fn response_format(mut self, format: ImageResponseFormat) -> Self {
+        self.response_format = Some(format);
+        self
+    }
+
+    pub fn conversation_id(mut self, id: Option<String>) -> Self {
+        self.conversation_id = id;
+        self
+    }
+
+    pub fn build(self, _provider: &Provider) -> Result<ImageRequest, ApiError> {
+        if let Some(n) = self.n
+            && !(1..=MAX_IMAGES_PER_REQUEST).contains(&n)
+        {
+            return Err(ApiError::InvalidRequest {
+                message: format!("n must be between 1 and {MAX_IMAGES_PER_REQUEST}, got {n}"),
+            });
+        }
+        if let Some(size) = self.size
+            && !SUPPORTED_IMAGE_SIZES.contains(&size)
+        {
+            return Err(ApiError::InvalidRequest {
+                message: format!("unsupported image size {size:?}"),
+            });
+        }
+
+        let mut body = json!({
+            "model": self.model,
+            "prompt": self.prompt,
+        });
+        if let Some(obj) = body.as_object_mut() {
+            if let Some(size) = self.size {
+                obj.insert("size".to_string(), json!(size));
+            }
+            if let Some(n) = self.n {
+                obj.insert("n".to_string(), json!(n));
+            }
+            if let Some(format) = self.response_format {
+                obj.insert("response_format".to_string(), json!(format.as_str()));
+            }
+        }
+
+        Ok(ImageRequest {
+            body,
+            headers: build_conversation_headers(self.conversation_id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::RetryConfig;
+    use crate::provider::WireApi;
+    use assert_matches::assert_matches;
+    use http::HeaderValue;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn provider() -> Provider {
+        Provider {
+            name: "openai".to_string(),
+            base_url: "https://api.openai.com/v1".to_string(),
+            query_params: None,
+            wire: WireApi::Chat,
+            headers: HeaderMap::new(),
+            retry: RetryConfig {
+                max_attempts: 1,
+                base_delay: Duration::from_millis(10),
+                retry_429: false,
+                retry_5xx: true,
+                retry_transport: true,
+            },
+            stream_idle_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn builds_image_request_with_optional_fields() {
+        let req = ImageRequestBuilder::new("gpt-image-1", "a lighthouse at dusk")
+            .size(Some("1024x1024"))
+            .n(Some(2))
+            .response_format(ImageResponseFormat::B64Json)
+            .conversation_id(Some("conv-1".to_string()))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body,
+            json!({
+                "model": "gpt-image-1",
+                "prompt": "a lighthouse at dusk",
+                "size": "1024x1024",
+                "n": 2,
+                "response_format": "b64_json",
+            })
+        );
+        assert_eq!(
+            req.headers.get("session_id"),
+            Some(&HeaderValue::from_static("conv-1"))
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_n() {
+        for n in [0, 11] {
+            let err = ImageRequestBuilder::new("gpt-image-1", "a lighthouse")
+                .n(Some(n))
+                .build(&provider())
+                .err()
+                .expect("invalid n");
+            assert_matches!(err, ApiError::InvalidRequest { .. });
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_size() {
+        let err = ImageRequestBuilder::new("gpt-image-1", "a lighthouse")
+            .size(Some("1000x1000"))
+            .build(&provider())
+            .err()
+            .expect("invalid size");
+        assert_matches!(err, ApiError::InvalidRequest { .. });
+    }
+}
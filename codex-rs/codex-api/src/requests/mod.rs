@@ -1,8 +1,30 @@
 pub mod chat;
+pub mod embeddings;
 pub(crate) mod headers;
+pub mod images;
+pub mod moderation;
 pub mod responses;
 
+pub use chat::AudioConfig;
 pub use chat::ChatRequest;
 pub use chat::ChatRequestBuilder;
+pub use chat::InstructionsPlacement;
+pub use chat::InstructionsRole;
+pub use chat::Modality;
+pub use chat::ReasoningEffort;
+pub use chat::ReasoningFieldStyle;
+pub use chat::ResponseFormat;
+pub use chat::ServiceTier;
+pub use chat::ToolChoice;
+pub use chat::ToolDef;
+pub use chat::ToolOutputFormat;
+pub use embeddings::EmbeddingsRequest;
+pub use embeddings::EmbeddingsRequestBuilder;
+pub use embeddings::EncodingFormat;
+pub use images::ImageRequest;
+pub use images::ImageRequestBuilder;
+pub use images::ImageResponseFormat;
+pub use moderation::ModerationRequest;
+pub use moderation::ModerationRequestBuilder;
 pub use responses::ResponsesRequest;
 pub use responses::ResponsesRequestBuilder;
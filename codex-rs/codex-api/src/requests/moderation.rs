@@ -0,0 +1,120 @@
+use crate::error::ApiError;
+use crate::provider::Provider;
+use crate::requests::headers::build_conversation_headers;
+use http::HeaderMap;
+use serde_json::Value;
+use serde_json::json;
+
+/// Assembled request body plus headers for a moderation request.
+pub struct ModerationRequest {
+    pub body: Value,
+    pub headers: HeaderMap,
+}
+
+pub struct ModerationRequestBuilder<'a> {
+    model: &'a str,
+    input: Vec<String>,
+    conversation_id: Option<String>,
+}
+
+impl<'a> ModerationRequestBuilder<'a> {
+    pub fn new(model: &'a str, input: Vec<String>) -> Self {
+        Self {
+            model,
+            input,
+            conversation_id: None,
+        }
+    }
+
+    pub fn conversation_id(mut self, id: Option<String>) -> Self {
+        self.conversation_id = id;
+        self
+    }
+
+    pub fn build(self, _provider: &Provider) -> Result<ModerationRequest, ApiError> {
+        if self.input.is_empty() {
+            return Err(ApiError::InvalidRequest {
+                message: "moderation input must not be empty".to_string(),
+            });
+        }
+
+        Ok(ModerationRequest {
+            body: json!({
+                "model": self.model,
+                "input": self.input,
+            }),
+            headers: build_conversation_headers(self.conversation_id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::RetryConfig;
+    use crate::provider::WireApi;
+    use assert_matches::assert_matches;
+    use http::HeaderValue;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+
+    fn provider() -> Provider {
+        Provider {
+            name: "openai".to_string(),
+            base_url: "https://api.openai.com/v1".to_string(),
+            query_params: None,
+            wire: WireApi::Chat,
+            headers: HeaderMap::new(),
+            retry: RetryConfig {
+                max_attempts: 1,
+                base_delay: Duration::from_millis(10),
+                retry_429: false,
+                retry_5xx: true,
+                retry_transport: true,
+            },
+            stream_idle_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn single_input_moderation_body() {
+        let req = ModerationRequestBuilder::new("omni-moderation-latest", vec!["hi".to_string()])
+            .conversation_id(Some("conv-1".to_string()))
+            .build(&provider())
+            .expect("request");
+
+        assert_eq!(
+            req.body,
+            json!({"model": "omni-moderation-latest", "input": ["hi"]})
+        );
+        assert_eq!(
+            req.headers.get("session_id"),
+            Some(&HeaderValue::from_static("conv-1"))
+        );
+    }
+
+    #[test]
+    fn multi_input_moderation_body() {
+        let req = ModerationRequestBuilder::new(
+            "omni-moderation-latest",
+            vec!["first".to_string(), "second".to_string()],
+        )
+        .build(&provider())
+        .expect("request");
+
+        assert_eq!(
+            req.body,
+            json!({"model": "omni-moderation-latest", "input": ["first", "second"]})
+        );
+    }
+
+    #[test]
+    fn rejects_empty_input() {
+        let err = ModerationRequestBuilder::new("omni-moderation-latest", Vec::new())
+            .build(&provider())
+            .err()
+            .expect("empty input error");
+
+        assert_matches!(err, ApiError::InvalidRequest { .. });
+    }
+}
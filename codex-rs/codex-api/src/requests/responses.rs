@@ -141,6 +141,7 @@ impl<'a> ResponsesRequestBuilder<'a> {
 
         let mut body = serde_json::to_value(&req)
             .map_err(|e| ApiError::Stream(format!("failed to encode responses request: {e}")))?;
+        strip_input_audio(&mut body);
 
         if store && provider.is_azure_responses_endpoint() {
             attach_item_ids(&mut body, input);
@@ -160,6 +161,19 @@ impl<'a> ResponsesRequestBuilder<'a> {
     }
 }
 
+/// Removes `input_audio` parts from message content. Audio is a Chat Completions input; the
+/// Responses API does not accept it in this shape.
+fn strip_input_audio(payload_json: &mut Value) {
+    let Some(Value::Array(items)) = payload_json.get_mut("input") else {
+        return;
+    };
+    for item in items {
+        if let Some(Value::Array(parts)) = item.get_mut("content") {
+            parts.retain(|part| part.get("type").and_then(Value::as_str) != Some("input_audio"));
+        }
+    }
+}
+
 fn attach_item_ids(payload_json: &mut Value, original_items: &[ResponseItem]) {
     let Some(input_value) = payload_json.get_mut("input") else {
         return;
@@ -192,6 +206,7 @@ mod tests {
     use super::*;
     use crate::provider::RetryConfig;
     use crate::provider::WireApi;
+    use codex_protocol::models::ContentItem;
     use codex_protocol::protocol::SubAgentSource;
     use http::HeaderValue;
     use pretty_assertions::assert_eq;
@@ -223,12 +238,14 @@ mod tests {
                 id: Some("m1".into()),
                 role: "assistant".into(),
                 content: Vec::new(),
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
                 id: None,
                 role: "assistant".into(),
                 content: Vec::new(),
+                name: None,
                 end_turn: None,
             },
         ];
@@ -260,4 +277,76 @@ mod tests {
             Some(&HeaderValue::from_static("review"))
         );
     }
+
+    #[test]
+    fn serializes_top_level_instructions_and_input_items() {
+        let provider = provider("openai", "https://api.openai.com/v1");
+        let input = vec![
+            ResponseItem::Message {
+                id: None,
+                role: "user".into(),
+                content: vec![ContentItem::InputText { text: "hi".into() }],
+                name: None,
+                end_turn: None,
+            },
+            ResponseItem::FunctionCall {
+                id: None,
+                name: "read_file".into(),
+                arguments: "{}".into(),
+                call_id: "call-1".into(),
+            },
+        ];
+
+        let request = ResponsesRequestBuilder::new("gpt-test", "inst", &input)
+            .build(&provider)
+            .expect("request");
+
+        assert_eq!(request.body.get("messages"), None);
+        assert_eq!(request.body["instructions"], Value::from("inst"));
+        assert_eq!(
+            request.body["input"],
+            serde_json::json!([
+                {
+                    "type": "message",
+                    "role": "user",
+                    "content": [{"type": "input_text", "text": "hi"}],
+                },
+                {
+                    "type": "function_call",
+                    "name": "read_file",
+                    "arguments": "{}",
+                    "call_id": "call-1",
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn drops_input_audio_from_messages() {
+        let provider = provider("openai", "https://api.openai.com/v1");
+        let input = vec![ResponseItem::Message {
+            id: None,
+            role: "user".into(),
+            content: vec![
+                ContentItem::InputText {
+                    text: "transcribe".into(),
+                },
+                ContentItem::InputAudio {
+                    data: "UklGRg==".into(),
+                    format: "wav".into(),
+                },
+            ],
+            name: None,
+            end_turn: None,
+        }];
+
+        let request = ResponsesRequestBuilder::new("gpt-test", "inst", &input)
+            .build(&provider)
+            .expect("request");
+
+        assert_eq!(
+            request.body["input"][0]["content"],
+            serde_json::json!([{"type": "input_text", "text": "transcribe"}])
+        );
+    }
 }
@@ -158,17 +158,8 @@ pub async fn process_chat_sse<S>(
 
         for choice in choices {
             if let Some(delta) = choice.get("delta") {
-                if let Some(reasoning) = delta.get("reasoning") {
-                    if let Some(text) = reasoning.as_str() {
-                        append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string())
-                            .await;
-                    } else if let Some(text) = reasoning.get("text").and_then(|v| v.as_str()) {
-                        append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string())
-                            .await;
-                    } else if let Some(text) = reasoning.get("content").and_then(|v| v.as_str()) {
-                        append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string())
-                            .await;
-                    }
+                if let Some(text) = reasoning_text(delta) {
+                    append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string()).await;
                 }
 
                 if let Some(content) = delta.get("content") {
@@ -245,15 +236,9 @@ pub async fn process_chat_sse<S>(
             }
 
             if let Some(message) = choice.get("message")
-                && let Some(reasoning) = message.get("reasoning")
+                && let Some(text) = reasoning_text(message)
             {
-                if let Some(text) = reasoning.as_str() {
-                    append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string()).await;
-                } else if let Some(text) = reasoning.get("text").and_then(|v| v.as_str()) {
-                    append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string()).await;
-                } else if let Some(text) = reasoning.get("content").and_then(|v| v.as_str()) {
-                    append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string()).await;
-                }
+                append_reasoning_text(&tx_event, &mut reasoning_item, text.to_string()).await;
             }
 
             let finish_reason = choice.get("finish_reason").and_then(|r| r.as_str());
@@ -330,6 +315,7 @@ async fn append_assistant_text(
             id: None,
             role: "assistant".to_string(),
             content: vec![],
+            name: None,
             end_turn: None,
         };
         *assistant_item = Some(item.clone());
@@ -346,6 +332,23 @@ async fn append_assistant_text(
     }
 }
 
+/// Reads reasoning from `reasoning_content` or `reasoning`; the latter may be a string or an
+/// object carrying `text` or `content`. `reasoning_content` wins when both are present so the
+/// same text is not collected twice.
+fn reasoning_text(value: &serde_json::Value) -> Option<&str> {
+    if let Some(text) = value
+        .get("reasoning_content")
+        .and_then(serde_json::Value::as_str)
+    {
+        return Some(text);
+    }
+    let reasoning = value.get("reasoning")?;
+    reasoning
+        .as_str()
+        .or_else(|| reasoning.get("text").and_then(serde_json::Value::as_str))
+        .or_else(|| reasoning.get("content").and_then(serde_json::Value::as_str))
+}
+
 async fn append_reasoning_text(
     tx_event: &mpsc::Sender<Result<ResponseEvent, ApiError>>,
     reasoning_item: &mut Option<ResponseItem>,
@@ -683,6 +686,46 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn collects_reasoning_content_deltas() {
+        let delta_a = json!({
+            "choices": [{
+                "delta": { "reasoning_content": "Let me " }
+            }]
+        });
+        let delta_b = json!({
+            "choices": [{
+                "delta": { "reasoning_content": "think.", "reasoning": "think." }
+            }]
+        });
+        let finish = json!({
+            "choices": [{
+                "finish_reason": "stop"
+            }]
+        });
+
+        let body = build_body(&[delta_a, delta_b, finish]);
+        let events = collect_events(&body).await;
+
+        let deltas: Vec<&str> = events
+            .iter()
+            .filter_map(|ev| match ev {
+                ResponseEvent::ReasoningContentDelta { delta, .. } => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, vec!["Let me ", "think."]);
+        assert_matches!(
+            events.iter().find_map(|ev| match ev {
+                ResponseEvent::OutputItemDone(ResponseItem::Reasoning { content, .. }) => {
+                    content.as_ref()
+                }
+                _ => None,
+            }),
+            Some(content) if content.len() == 2
+        );
+    }
+
     #[tokio::test]
     async fn drops_partial_tool_calls_on_stop_finish_reason() {
         let delta_tool = json!({
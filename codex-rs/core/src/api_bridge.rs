@@ -22,6 +22,9 @@ pub(crate) fn map_api_error(err: ApiError) -> CodexErr {
         ApiError::QuotaExceeded => CodexErr::QuotaExceeded,
         ApiError::UsageNotIncluded => CodexErr::UsageNotIncluded,
         ApiError::Retryable { message, delay } => CodexErr::Stream(message, delay),
+        ApiError::RateLimited { retry_after } => {
+            CodexErr::Stream("rate limited".to_string(), retry_after)
+        }
         ApiError::Stream(msg) => CodexErr::Stream(msg, None),
         ApiError::Api { status, message } => CodexErr::UnexpectedStatus(UnexpectedResponseError {
             status,
@@ -30,6 +33,19 @@ pub(crate) fn map_api_error(err: ApiError) -> CodexErr {
             request_id: None,
         }),
         ApiError::InvalidRequest { message } => CodexErr::InvalidRequest(message),
+        err @ (ApiError::HistoryTooLong { .. }
+        | ApiError::DanglingToolOutput { .. }
+        | ApiError::InvalidImageUrl { .. }
+        | ApiError::UnsupportedImageMime { .. }
+        | ApiError::DuplicateToolCallId { .. }
+        | ApiError::TooManyImages { .. }
+        | ApiError::InstructionsTooLarge { .. }
+        | ApiError::ReasoningOnUserMessage { .. }
+        | ApiError::EmptyInput
+        | ApiError::TooManyTools { .. }
+        | ApiError::UnresolvedToolCall { .. }
+        | ApiError::ReasoningOptionsWithoutReasoning
+        | ApiError::ImageTooLarge { .. }) => CodexErr::InvalidRequest(err.to_string()),
         ApiError::Transport(transport) => match transport {
             TransportError::Http {
                 status,
@@ -1767,6 +1767,7 @@ impl Session {
             content: vec![ContentItem::InputText {
                 text: format!("Warning: {}", message.into()),
             }],
+            name: None,
             end_turn: None,
         };
 
@@ -3990,6 +3991,7 @@ mod tests {
             content: vec![ContentItem::InputText {
                 text: text.to_string(),
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -4271,6 +4273,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "turn 1 user".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -4279,6 +4282,7 @@ mod tests {
                 content: vec![ContentItem::OutputText {
                     text: "turn 1 assistant".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
         ];
@@ -4291,6 +4295,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "turn 2 user".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -4299,6 +4304,7 @@ mod tests {
                 content: vec![ContentItem::OutputText {
                     text: "turn 2 assistant".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
         ];
@@ -4331,6 +4337,7 @@ mod tests {
             content: vec![ContentItem::InputText {
                 text: "turn 1 user".to_string(),
             }],
+            name: None,
             end_turn: None,
         }];
         sess.record_into_history(&turn_1, tc.as_ref()).await;
@@ -5261,6 +5268,7 @@ mod tests {
             content: vec![ContentItem::InputText {
                 text: "first user".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         live_history.record_items(std::iter::once(&user1), turn_context.truncation_policy);
@@ -5272,6 +5280,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: "assistant reply one".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         live_history.record_items(std::iter::once(&assistant1), turn_context.truncation_policy);
@@ -5297,6 +5306,7 @@ mod tests {
             content: vec![ContentItem::InputText {
                 text: "second user".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         live_history.record_items(std::iter::once(&user2), turn_context.truncation_policy);
@@ -5308,6 +5318,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: "assistant reply two".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         live_history.record_items(std::iter::once(&assistant2), turn_context.truncation_policy);
@@ -5333,6 +5344,7 @@ mod tests {
             content: vec![ContentItem::InputText {
                 text: "third user".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         live_history.record_items(std::iter::once(&user3), turn_context.truncation_policy);
@@ -5344,6 +5356,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: "assistant reply three".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         live_history.record_items(std::iter::once(&assistant3), turn_context.truncation_policy);
@@ -213,7 +213,7 @@ pub fn content_items_to_text(content: &[ContentItem]) -> Option<String> {
                     pieces.push(text.as_str());
                 }
             }
-            ContentItem::InputImage { .. } => {}
+            ContentItem::InputImage { .. } | ContentItem::InputAudio { .. } => {}
         }
     }
     if pieces.is_empty() {
@@ -309,6 +309,7 @@ fn build_compacted_history_with_limit(
             content: vec![ContentItem::InputText {
                 text: message.clone(),
             }],
+            name: None,
             end_turn: None,
         });
     }
@@ -323,6 +324,7 @@ fn build_compacted_history_with_limit(
         id: None,
         role: "user".to_string(),
         content: vec![ContentItem::InputText { text: summary_text }],
+        name: None,
         end_turn: None,
     });
 
@@ -412,6 +414,7 @@ mod tests {
                 content: vec![ContentItem::OutputText {
                     text: "ignored".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -420,6 +423,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "first".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Other,
@@ -440,6 +444,7 @@ mod tests {
                     text: "# AGENTS.md instructions for project\n\n<INSTRUCTIONS>\ndo things\n</INSTRUCTIONS>"
                         .to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -448,6 +453,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "<ENVIRONMENT_CONTEXT>cwd=/tmp</ENVIRONMENT_CONTEXT>".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -456,6 +462,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "real user message".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
         ];
@@ -541,6 +548,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: marker.clone(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -549,6 +557,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "real user message".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
         ];
@@ -359,7 +359,7 @@ pub(crate) fn is_user_turn_boundary(item: &ResponseItem) -> bool {
                     return false;
                 }
             }
-            ContentItem::InputImage { .. } => {}
+            ContentItem::InputImage { .. } | ContentItem::InputAudio { .. } => {}
         }
     }
 
@@ -23,6 +23,7 @@ fn assistant_msg(text: &str) -> ResponseItem {
         content: vec![ContentItem::OutputText {
             text: text.to_string(),
         }],
+        name: None,
         end_turn: None,
     }
 }
@@ -42,6 +43,7 @@ fn user_msg(text: &str) -> ResponseItem {
         content: vec![ContentItem::OutputText {
             text: text.to_string(),
         }],
+        name: None,
         end_turn: None,
     }
 }
@@ -53,6 +55,7 @@ fn user_input_text_msg(text: &str) -> ResponseItem {
         content: vec![ContentItem::InputText {
             text: text.to_string(),
         }],
+        name: None,
         end_turn: None,
     }
 }
@@ -96,6 +99,7 @@ fn filters_non_api_messages() {
         content: vec![ContentItem::OutputText {
             text: "ignored".to_string(),
         }],
+        name: None,
         end_turn: None,
     };
     let reasoning = reasoning_msg("thinking...");
@@ -126,6 +130,7 @@ fn filters_non_api_messages() {
                 content: vec![ContentItem::OutputText {
                     text: "hi".to_string()
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -134,6 +139,7 @@ fn filters_non_api_messages() {
                 content: vec![ContentItem::OutputText {
                     text: "hello".to_string()
                 }],
+                name: None,
                 end_turn: None,
             }
         ]
@@ -261,6 +267,7 @@ fn replace_last_turn_images_does_not_touch_user_images() {
         content: vec![ContentItem::InputImage {
             image_url: "data:image/png;base64,AAA".to_string(),
         }],
+        name: None,
         end_turn: None,
     }];
     let mut history = create_history_with_items(items.clone());
@@ -57,6 +57,8 @@ fn parse_user_message(message: &[ContentItem]) -> Option<UserMessageItem> {
                     image_url: image_url.clone(),
                 });
             }
+            // User input has no audio counterpart, so the clip is not surfaced.
+            ContentItem::InputAudio { .. } => {}
             ContentItem::OutputText { text } => {
                 if is_session_prefix(text) {
                     return None;
@@ -176,6 +178,7 @@ mod tests {
                     image_url: img2.clone(),
                 },
             ],
+            name: None,
             end_turn: None,
         };
 
@@ -218,6 +221,7 @@ mod tests {
                     text: user_text.clone(),
                 },
             ],
+            name: None,
             end_turn: None,
         };
 
@@ -259,6 +263,7 @@ mod tests {
                     text: user_text.clone(),
                 },
             ],
+            name: None,
             end_turn: None,
         };
 
@@ -288,6 +293,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "<user_instructions>test_text</user_instructions>".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -296,6 +302,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "<environment_context>test_text</environment_context>".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -304,6 +311,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "# AGENTS.md instructions for test_directory\n\n<INSTRUCTIONS>\ntest_text\n</INSTRUCTIONS>".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -313,6 +321,7 @@ mod tests {
                     text: "<skill>\n<name>demo</name>\n<path>skills/demo/SKILL.md</path>\nbody\n</skill>"
                         .to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
             ResponseItem::Message {
@@ -321,6 +330,7 @@ mod tests {
                 content: vec![ContentItem::InputText {
                     text: "<user_shell_command>echo 42</user_shell_command>".to_string(),
                 }],
+                name: None,
                 end_turn: None,
             },
         ];
@@ -339,6 +349,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: "Hello from Codex".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
 
@@ -38,6 +38,7 @@ impl From<UserInstructions> for ResponseItem {
                     contents = ui.text
                 ),
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -72,6 +73,7 @@ impl From<SkillInstructions> for ResponseItem {
                     si.name, si.path, si.contents
                 ),
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -901,6 +901,7 @@ async fn test_updated_at_uses_file_mtime() -> Result<()> {
                 content: vec![ContentItem::OutputText {
                     text: format!("reply-{idx}"),
                 }],
+                name: None,
                 end_turn: None,
             }),
         };
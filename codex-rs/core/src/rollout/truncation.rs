@@ -85,6 +85,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: text.to_string(),
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -96,6 +97,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: text.to_string(),
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -264,6 +264,7 @@ impl Session {
                         "{TURN_ABORTED_OPEN_TAG}\n{TURN_ABORTED_INTERRUPTED_GUIDANCE}\n</turn_aborted>"
                     ),
                 }],
+                name: None,
                 end_turn: None,
             };
             self.record_into_history(std::slice::from_ref(&marker), task.turn_context.as_ref())
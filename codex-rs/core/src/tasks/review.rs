@@ -221,6 +221,7 @@ pub(crate) async fn exit_review_mode(
                 id: Some(REVIEW_USER_MESSAGE_ID.to_string()),
                 role: "user".to_string(),
                 content: vec![ContentItem::InputText { text: user_message }],
+                name: None,
                 end_turn: None,
             }],
         )
@@ -240,6 +241,7 @@ pub(crate) async fn exit_review_mode(
                 content: vec![ContentItem::OutputText {
                     text: assistant_message,
                 }],
+                name: None,
                 end_turn: None,
             },
         )
@@ -461,6 +461,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: text.to_string(),
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -471,6 +472,7 @@ mod tests {
             content: vec![ContentItem::OutputText {
                 text: text.to_string(),
             }],
+            name: None,
             end_turn: None,
         }
     }
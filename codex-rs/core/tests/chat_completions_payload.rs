@@ -138,6 +138,7 @@ fn user_message(text: &str) -> ResponseItem {
         content: vec![ContentItem::InputText {
             text: text.to_string(),
         }],
+        name: None,
         end_turn: None,
     }
 }
@@ -149,6 +150,7 @@ fn assistant_message(text: &str) -> ResponseItem {
         content: vec![ContentItem::OutputText {
             text: text.to_string(),
         }],
+        name: None,
         end_turn: None,
     }
 }
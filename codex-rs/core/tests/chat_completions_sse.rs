@@ -112,6 +112,7 @@ async fn run_stream_with_bytes(sse_body: &[u8]) -> Vec<ResponseEvent> {
         content: vec![ContentItem::InputText {
             text: "hello".to_string(),
         }],
+        name: None,
         end_turn: None,
     }];
 
@@ -107,6 +107,7 @@ async fn responses_stream_includes_subagent_header_on_review() {
         content: vec![ContentItem::InputText {
             text: "hello".into(),
         }],
+        name: None,
         end_turn: None,
     }];
 
@@ -206,6 +207,7 @@ async fn responses_stream_includes_subagent_header_on_other() {
         content: vec![ContentItem::InputText {
             text: "hello".into(),
         }],
+        name: None,
         end_turn: None,
     }];
 
@@ -363,6 +365,7 @@ async fn responses_respects_model_info_overrides_from_config() {
         content: vec![ContentItem::InputText {
             text: "hello".into(),
         }],
+        name: None,
         end_turn: None,
     }];
 
@@ -196,6 +196,7 @@ async fn resume_includes_initial_messages_and_sends_prior_items() {
         content: vec![codex_protocol::models::ContentItem::InputText {
             text: "resumed user message".to_string(),
         }],
+        name: None,
         end_turn: None,
     };
     let prior_user_json = serde_json::to_value(&prior_user).unwrap();
@@ -217,6 +218,7 @@ async fn resume_includes_initial_messages_and_sends_prior_items() {
         content: vec![codex_protocol::models::ContentItem::OutputText {
             text: "resumed system instruction".to_string(),
         }],
+        name: None,
         end_turn: None,
     };
     let prior_system_json = serde_json::to_value(&prior_system).unwrap();
@@ -238,6 +240,7 @@ async fn resume_includes_initial_messages_and_sends_prior_items() {
         content: vec![codex_protocol::models::ContentItem::OutputText {
             text: "resumed assistant message".to_string(),
         }],
+        name: None,
         end_turn: None,
     };
     let prior_item_json = serde_json::to_value(&prior_item).unwrap();
@@ -1209,6 +1212,7 @@ async fn azure_responses_request_includes_store_and_reasoning_ids() {
         content: vec![ContentItem::OutputText {
             text: "message".into(),
         }],
+        name: None,
         end_turn: None,
     });
     prompt.input.push(ResponseItem::WebSearchCall {
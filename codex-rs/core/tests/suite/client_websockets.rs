@@ -172,6 +172,7 @@ fn message_item(text: &str) -> ResponseItem {
         id: None,
         role: "user".into(),
         content: vec![ContentItem::InputText { text: text.into() }],
+        name: None,
         end_turn: None,
     }
 }
@@ -1451,6 +1451,7 @@ async fn auto_compact_runs_after_resume_when_token_usage_is_over_limit() {
             content: vec![codex_protocol::models::ContentItem::OutputText {
                 text: remote_summary.to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         codex_protocol::models::ResponseItem::Compaction {
@@ -2274,6 +2275,7 @@ async fn auto_compact_counts_encrypted_reasoning_before_last_user() {
             content: vec![codex_protocol::models::ContentItem::OutputText {
                 text: "REMOTE_COMPACT_SUMMARY".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         codex_protocol::models::ResponseItem::Compaction {
@@ -2394,6 +2396,7 @@ async fn auto_compact_runs_when_reasoning_header_clears_between_turns() {
             content: vec![codex_protocol::models::ContentItem::OutputText {
                 text: "REMOTE_COMPACT_SUMMARY".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         codex_protocol::models::ResponseItem::Compaction {
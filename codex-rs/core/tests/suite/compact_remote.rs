@@ -61,6 +61,7 @@ async fn remote_compact_replaces_history_for_followups() -> Result<()> {
             content: vec![ContentItem::InputText {
                 text: "REMOTE_COMPACTED_SUMMARY".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         ResponseItem::Compaction {
@@ -183,6 +184,7 @@ async fn remote_compact_runs_automatically() -> Result<()> {
             content: vec![ContentItem::InputText {
                 text: "REMOTE_COMPACTED_SUMMARY".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         ResponseItem::Compaction {
@@ -250,6 +252,7 @@ async fn remote_manual_compact_emits_context_compaction_items() -> Result<()> {
             content: vec![ContentItem::InputText {
                 text: "REMOTE_COMPACTED_SUMMARY".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         ResponseItem::Compaction {
@@ -351,6 +354,7 @@ async fn remote_compact_persists_replacement_history_in_rollout() -> Result<()>
             content: vec![ContentItem::InputText {
                 text: "COMPACTED_USER_SUMMARY".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
         ResponseItem::Compaction {
@@ -362,6 +366,7 @@ async fn remote_compact_persists_replacement_history_in_rollout() -> Result<()>
             content: vec![ContentItem::OutputText {
                 text: "COMPACTED_ASSISTANT_NOTE".to_string(),
             }],
+            name: None,
             end_turn: None,
         },
     ];
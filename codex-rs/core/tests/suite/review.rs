@@ -529,6 +529,7 @@ async fn review_input_isolated_from_parent_history() {
             content: vec![codex_protocol::models::ContentItem::InputText {
                 text: "parent: earlier user message".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         let user_json = serde_json::to_value(&user).unwrap();
@@ -548,6 +549,7 @@ async fn review_input_isolated_from_parent_history() {
             content: vec![codex_protocol::models::ContentItem::OutputText {
                 text: "parent: assistant reply".to_string(),
             }],
+            name: None,
             end_turn: None,
         };
         let assistant_json = serde_json::to_value(&assistant).unwrap();
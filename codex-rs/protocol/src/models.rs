@@ -67,9 +67,21 @@ pub enum ResponseInputItem {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema, TS)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ContentItem {
-    InputText { text: String },
-    InputImage { image_url: String },
-    OutputText { text: String },
+    InputText {
+        text: String,
+    },
+    InputImage {
+        image_url: String,
+    },
+    /// Base64-encoded audio in `format` (e.g. `wav` or `mp3`). Only the Chat Completions wire
+    /// format forwards it, as an `input_audio` part; Responses requests drop it.
+    InputAudio {
+        data: String,
+        format: String,
+    },
+    OutputText {
+        text: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema, TS)]
@@ -81,6 +93,11 @@ pub enum ResponseItem {
         id: Option<String>,
         role: String,
         content: Vec<ContentItem>,
+        /// Participant name in multi-agent transcripts. Like `id` it is never serialized; the
+        /// Chat Completions request builder sends it as the message's `name` field.
+        #[serde(default, skip_serializing)]
+        #[ts(skip)]
+        name: Option<String>,
         // Do not use directly, no available consistently across all providers.
         #[serde(default, skip_serializing_if = "Option::is_none")]
         #[ts(optional)]
@@ -410,6 +427,7 @@ impl From<DeveloperInstructions> for ResponseItem {
             content: vec![ContentItem::InputText {
                 text: di.into_text(),
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -567,6 +585,7 @@ impl From<ResponseInputItem> for ResponseItem {
                 role,
                 content,
                 id: None,
+                name: None,
                 end_turn: None,
             },
             ResponseInputItem::FunctionCallOutput { call_id, output } => {
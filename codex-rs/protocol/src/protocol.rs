@@ -1670,6 +1670,7 @@ impl From<CompactedItem> for ResponseItem {
             content: vec![ContentItem::OutputText {
                 text: value.message,
             }],
+            name: None,
             end_turn: None,
         }
     }
@@ -88,7 +88,9 @@ fn extract_user_message_text(item: &ResponseItem) -> Option<String> {
         .iter()
         .filter_map(|content_item| match content_item {
             ContentItem::InputText { text } => Some(text.as_str()),
-            ContentItem::InputImage { .. } | ContentItem::OutputText { .. } => None,
+            ContentItem::InputImage { .. }
+            | ContentItem::InputAudio { .. }
+            | ContentItem::OutputText { .. } => None,
         })
         .filter(|text| !is_local_image_open_tag_text(text) && !is_local_image_close_tag_text(text))
         .collect();
@@ -145,6 +147,7 @@ mod tests {
                     image_url: "https://example.com/image.png".to_string(),
                 },
             ],
+            name: None,
             end_turn: None,
         };
         let actual = extract_user_message_text(&item);